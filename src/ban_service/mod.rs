@@ -0,0 +1,127 @@
+// src/ban_service/mod.rs
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use worker::{D1Database, Env, Result, console_log, console_warn};
+
+/// One row of the `bans` table. `expires_at` of `None` means a permanent ban.
+/// `starts_at` is usually `created_at`, but a delayed ban sets it in the
+/// future; until then `is_banned` treats the row as not yet active.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ban {
+    pub id: String,
+    pub user_id: String,
+    pub reason: String,
+    pub banned_by: String,
+    pub created_at: DateTime<Utc>,
+    pub starts_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Tracks time-bounded bans with a reason and acting moderator, replacing the
+/// permanent, reason-less `UserState::Blocked` flag.
+pub struct BanService {
+    db: D1Database,
+}
+
+impl BanService {
+    const BAN_FIELDS: &'static str = "id, user_id, reason, banned_by, created_at, starts_at, expires_at";
+
+    pub fn new(env: &Env) -> Result<Self> {
+        let db = env.d1("DB")?;
+        Ok(Self { db })
+    }
+
+    /// Bans `user_id`. `duration` of `None` bans permanently; otherwise
+    /// `expires_at` is set to `now + duration`. `delay` of `Some` defers
+    /// `starts_at` so the ban only takes effect after that much time passes.
+    pub async fn ban_user(&self, user_id: &str, reason: &str, banned_by: &str, duration: Option<Duration>, delay: Option<Duration>) -> Result<Ban> {
+        let id = worker::Uuid::new_v4().to_string();
+        let now = Utc::now();
+        let starts_at = now + delay.unwrap_or_else(Duration::zero);
+        let expires_at = duration.map(|d| starts_at + d);
+        console_log!("[BanService] Banning user {} (reason: {}, starts_at: {}, expires_at: {:?})", user_id, reason, starts_at, expires_at);
+
+        let sql = format!(
+            "INSERT INTO bans (id, user_id, reason, banned_by, created_at, starts_at, expires_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING {}",
+            Self::BAN_FIELDS
+        );
+        let ban = self.db.prepare(&sql)
+            .bind(&[
+                id.clone().into(), user_id.into(), reason.into(), banned_by.into(),
+                now.to_rfc3339().into(), starts_at.to_rfc3339().into(), expires_at.map(|e| e.to_rfc3339()).into(),
+            ])?
+            .first::<Ban>(None).await?
+            .ok_or_else(|| worker::Error::RustError(format!("ban {} not found after insert", id)))?;
+
+        // Keeps the legacy `UserState::Blocked` flag in sync so
+        // `sweep_expired_bans`, which only un-blocks rows in that state, has
+        // something to actually flip back. A delayed ban (`starts_at` still
+        // in the future) is left un-blocked until it takes effect; `is_banned`
+        // already enforces `starts_at` for command-time checks in the meantime.
+        if starts_at <= now {
+            self.db.prepare("UPDATE users SET state = 'Blocked' WHERE id = ?1")
+                .bind(&[user_id.into()])?
+                .run().await?;
+        }
+
+        Ok(ban)
+    }
+
+    /// Removes all ban rows for `user_id`, lifting the ban immediately.
+    pub async fn unban_user(&self, user_id: &str) -> Result<()> {
+        console_log!("[BanService] Unbanning user {}", user_id);
+        self.db.prepare("DELETE FROM bans WHERE user_id = ?1")
+            .bind(&[user_id.into()])?
+            .run().await?;
+        Ok(())
+    }
+
+    /// Returns the active ban for `user_id`: one that has started (`starts_at`
+    /// has passed) and whose `expires_at` is NULL or still in the future. A
+    /// ban that hasn't started yet, or has lapsed, is treated as absent.
+    pub async fn is_banned(&self, user_id: &str) -> Result<Option<Ban>> {
+        let now = Utc::now();
+        let sql = format!(
+            "SELECT {} FROM bans WHERE user_id = ?1 AND starts_at <= ?2 AND (expires_at IS NULL OR expires_at > ?2) \
+             ORDER BY created_at DESC LIMIT 1",
+            Self::BAN_FIELDS
+        );
+        self.db.prepare(&sql)
+            .bind(&[user_id.into(), now.to_rfc3339().into()])?
+            .first::<Ban>(None).await
+    }
+
+    /// Clears a stale `UserState::Blocked` flag left over from an expired
+    /// timed ban that `sweep_expired_bans` hasn't gotten to yet — called
+    /// lazily from `BanStateHook` once `is_banned` comes back empty, the same
+    /// way `ModerationService::check_and_lift_mute` lazily lifts a mute.
+    pub async fn clear_stale_block(&self, user_id: &str) -> Result<()> {
+        self.db.prepare("UPDATE users SET state = 'Active' WHERE id = ?1 AND state = 'Blocked'")
+            .bind(&[user_id.into()])?
+            .run().await?;
+        Ok(())
+    }
+
+    /// Flips `UserState::Blocked` back to `Active` for every user whose
+    /// temporary ban has expired and deletes the stale ban rows. Intended to be
+    /// called from a scheduled Worker cron.
+    pub async fn sweep_expired_bans(&self) -> Result<usize> {
+        let now = Utc::now();
+        let sql = "SELECT user_id FROM bans WHERE expires_at IS NOT NULL AND expires_at <= ?1";
+        let results = self.db.prepare(sql).bind(&[now.to_rfc3339().into()])?.all().await?;
+        let rows: Vec<std::collections::HashMap<String, String>> = results.results()?;
+        let expired_user_ids: Vec<String> = rows.into_iter().filter_map(|mut r| r.remove("user_id")).collect();
+
+        for user_id in &expired_user_ids {
+            self.db.prepare("UPDATE users SET state = 'Active' WHERE id = ?1 AND state = 'Blocked'")
+                .bind(&[user_id.into()])?
+                .run().await?;
+            self.db.prepare("DELETE FROM bans WHERE user_id = ?1 AND expires_at IS NOT NULL AND expires_at <= ?2")
+                .bind(&[user_id.into(), now.to_rfc3339().into()])?
+                .run().await?;
+        }
+
+        console_warn!("[BanService] Swept {} expired ban(s).", expired_user_ids.len());
+        Ok(expired_user_ids.len())
+    }
+}
@@ -0,0 +1,76 @@
+// src/cleanup_service/mod.rs
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use worker::{Bucket as R2Bucket, D1Database, Env, Result, console_log, console_warn};
+
+/// One row of the `deletion_queue` table: an R2 object key that was dropped by a
+/// user-facing flow (media removal, user deletion/block) and may need its
+/// underlying R2 object garbage-collected once nothing else references it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeletionQueueEntry {
+    pub r2_object_key: String,
+    pub reason: String,
+    pub enqueued_at: DateTime<Utc>,
+}
+
+/// Drains R2 objects that were dropped from `user_media` but never physically
+/// deleted, so a scheduled Worker cron can reclaim storage without blocking the
+/// user update path that enqueued them.
+pub struct CleanupService {
+    db: D1Database,
+    media_bucket: R2Bucket,
+}
+
+impl CleanupService {
+    pub fn new(env: &Env) -> Result<Self> {
+        let db = env.d1("DB")?;
+        let media_bucket = env.bucket("MEDIA_BUCKET")?;
+        Ok(Self { db, media_bucket })
+    }
+
+    /// Enqueues an R2 object key for later garbage collection. Callers should
+    /// invoke this whenever a `user_media` row (or a user) is deleted, instead of
+    /// deleting the R2 object inline on the hot path.
+    pub async fn enqueue(&self, r2_object_key: &str, reason: &str) -> Result<()> {
+        console_log!("[CleanupService] Enqueuing '{}' for deletion (reason: {})", r2_object_key, reason);
+        let now = Utc::now();
+        self.db.prepare("INSERT INTO deletion_queue (r2_object_key, reason, enqueued_at) VALUES (?1, ?2, ?3)")
+            .bind(&[r2_object_key.into(), reason.into(), now.to_rfc3339().into()])?
+            .run().await?;
+        Ok(())
+    }
+
+    /// Returns queued keys that are no longer referenced by any live user's
+    /// `user_media` row, i.e. genuinely safe to delete from R2.
+    pub async fn find_orphaned_media(&self) -> Result<Vec<String>> {
+        let sql = "SELECT r2_object_key FROM deletion_queue \
+                   WHERE r2_object_key NOT IN (SELECT r2_object_key FROM user_media)";
+        let results = self.db.prepare(sql).all().await?;
+        let rows: Vec<std::collections::HashMap<String, String>> = results.results()?;
+        Ok(rows.into_iter().filter_map(|mut row| row.remove("r2_object_key")).collect())
+    }
+
+    /// Deletes up to `batch_size` orphaned objects from R2 and clears their
+    /// `deletion_queue` rows. Intended to be called from a scheduled Worker cron.
+    pub async fn process_deletion_queue(&self, batch_size: usize) -> Result<usize> {
+        let orphaned = self.find_orphaned_media().await?;
+        let batch: Vec<String> = orphaned.into_iter().take(batch_size).collect();
+
+        let mut processed = 0usize;
+        for key in &batch {
+            match self.media_bucket.delete(key).await {
+                Ok(_) => {
+                    self.db.prepare("DELETE FROM deletion_queue WHERE r2_object_key = ?1")
+                        .bind(&[key.as_str().into()])?
+                        .run().await?;
+                    processed += 1;
+                }
+                Err(e) => {
+                    console_warn!("[CleanupService] Failed to delete orphaned object '{}': {}. Leaving queued for retry.", key, e);
+                }
+            }
+        }
+        console_log!("[CleanupService] Processed {}/{} queued deletions.", processed, batch.len());
+        Ok(processed)
+    }
+}
@@ -0,0 +1,266 @@
+// src/command_hooks/mod.rs
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+use chrono::{Duration, Utc};
+use serde_json::json;
+use worker::{Env, Response, Result, console_log, console_warn};
+
+use crate::config_service::EnvironmentConfig;
+use crate::moderation_service::ModerationService;
+use crate::rbac_service::RBACService;
+use crate::user_service::{User, UserService, UserState};
+
+/// Everything a `CommandHook` needs to inspect the in-flight command, shared
+/// read-only across every hook in a `HookChain`.
+pub struct CommandContext<'a> {
+    pub env: &'a Env,
+    pub user_service: &'a UserService,
+    pub rbac_service: &'a RBACService,
+    pub moderation_service: &'a ModerationService,
+    pub env_config: &'a EnvironmentConfig,
+    pub domain_user: &'a User,
+    pub chat_id: i64,
+    pub command_str: &'a str,
+}
+
+/// What a hook's `before` phase decided: proceed to the next hook (and
+/// eventually the handler), or short-circuit the pipeline with its own
+/// response.
+pub enum HookFlow {
+    Continue,
+    ShortCircuit(Response),
+}
+
+/// A cross-cutting behavior that runs around a dispatched command instead of
+/// being duplicated inside every handler — session timeouts, RBAC, ban/mute
+/// enforcement, interaction recording, and so on. `before` can veto the
+/// command; `after` observes the response once the handler has produced one.
+/// Adding a new behavior like rate-limiting is a matter of implementing this
+/// trait and registering it in the `HookChain`, not editing every handler.
+///
+/// This is a deliberate merge of two overlapping requests: the original
+/// `before`/`after` + `HookFlow` shape is what was asked for, plus a
+/// registry mapping commands (or a wildcard) to an ordered hook list, plus
+/// built-in RBAC and ban-state hooks that stop a command uniformly. Rather
+/// than stand up a second, parallel `run(&self, ctx) -> HookOutcome`
+/// trait with its own `Continue`/`Reject(String)` enum alongside this one,
+/// the ban/RBAC hooks were built directly on this trait: `HookFlow::ShortCircuit`
+/// carries a full `Response` instead of a bare rejection string, which is a
+/// strict superset (a hook can still just wrap a plain text message in one,
+/// as every hook below does) without forcing callers to juggle two hook
+/// traits and two chains for what is the same cross-cutting-behavior need.
+pub trait CommandHook {
+    fn before<'a>(&'a self, ctx: &'a CommandContext<'a>) -> Pin<Box<dyn Future<Output = Result<HookFlow>> + 'a>>;
+
+    fn after<'a>(&'a self, _ctx: &'a CommandContext<'a>, _response: &'a Response) -> Pin<Box<dyn Future<Output = Result<()>> + 'a>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Runs a fixed, ordered set of `CommandHook`s around a command handler:
+/// every hook's `before` in order (stopping early on a short-circuit), then
+/// the handler itself, then every hook's `after` in order.
+pub struct HookChain {
+    hooks: Vec<Box<dyn CommandHook>>,
+}
+
+impl HookChain {
+    pub fn new(hooks: Vec<Box<dyn CommandHook>>) -> Self {
+        Self { hooks }
+    }
+
+    pub async fn run<'a, F, Fut>(&self, ctx: &'a CommandContext<'a>, handler: F) -> Result<Response>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Response>>,
+    {
+        for hook in &self.hooks {
+            match hook.before(ctx).await? {
+                HookFlow::Continue => {}
+                HookFlow::ShortCircuit(response) => return Ok(response),
+            }
+        }
+
+        let response = handler().await?;
+
+        for hook in &self.hooks {
+            hook.after(ctx, &response).await?;
+        }
+
+        Ok(response)
+    }
+}
+
+/// Maps a command name to the ordered `HookChain` that should guard it,
+/// falling back to a wildcard chain when the command has no entry of its
+/// own. Lets a new cross-cutting policy apply to every command by
+/// registering it once in the wildcard chain, or to a handful of commands by
+/// overriding just those with `for_command`, instead of editing every
+/// handler or the wildcard list itself.
+pub struct HookRegistry {
+    wildcard: HookChain,
+    overrides: HashMap<&'static str, HookChain>,
+}
+
+impl HookRegistry {
+    pub fn new(wildcard: HookChain) -> Self {
+        Self { wildcard, overrides: HashMap::new() }
+    }
+
+    /// Registers `chain` to run instead of the wildcard chain for `command`.
+    pub fn for_command(mut self, command: &'static str, chain: HookChain) -> Self {
+        self.overrides.insert(command, chain);
+        self
+    }
+
+    pub async fn run<'a, F, Fut>(&self, ctx: &'a CommandContext<'a>, handler: F) -> Result<Response>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Response>>,
+    {
+        self.overrides.get(ctx.command_str).unwrap_or(&self.wildcard).run(ctx, handler).await
+    }
+}
+
+/// Centralizes the `signed_duration_since(last_interaction_at)` check that
+/// used to be duplicated in `dispatch_command` and `handle_start_command`.
+/// Short-circuits to a "please /start again" message once a session has
+/// timed out — except for `/start` itself, which is the re-auth flow.
+pub struct SessionTimeoutHook;
+
+impl CommandHook for SessionTimeoutHook {
+    fn before<'a>(&'a self, ctx: &'a CommandContext<'a>) -> Pin<Box<dyn Future<Output = Result<HookFlow>> + 'a>> {
+        Box::pin(async move {
+            let time_since_last_interaction = Utc::now().signed_duration_since(ctx.domain_user.last_interaction_at);
+            let timed_out = time_since_last_interaction > Duration::seconds(ctx.env_config.session_timeout_seconds)
+                && ctx.domain_user.state != UserState::Onboarding;
+
+            if !timed_out {
+                return Ok(HookFlow::Continue);
+            }
+
+            console_log!("[SessionTimeoutHook] User {} session timed out for '{}'. Last seen {} mins ago.",
+                ctx.domain_user.id, ctx.command_str, time_since_last_interaction.num_minutes());
+
+            if ctx.command_str == "/start" {
+                return Ok(HookFlow::Continue);
+            }
+
+            Ok(HookFlow::ShortCircuit(Response::from_json(&json!({
+                "method": "sendMessage", "chat_id": ctx.chat_id,
+                "text": "Your session has expired. Please use /start to continue."
+            }))?))
+        })
+    }
+}
+
+/// Enforces the ban system ahead of every command, including the legacy
+/// `UserState::Blocked` flag the `bans` table is gradually replacing. Runs
+/// before `RbacHook` so a banned user sees "banned", not a generic
+/// permission denial. Fails open on a lookup error, the same as the
+/// best-effort mute check `dispatch_command` runs before building this
+/// chain — Workers have no background timer to lift an expired ban either,
+/// so `is_banned` (not the `Blocked` flag alone) is the source of truth on
+/// every command; a stale `Blocked` flag left by an un-swept expired ban is
+/// lazily cleared the same way `MuteStateHook` lazily lifts an expired mute.
+pub struct BanStateHook;
+
+impl CommandHook for BanStateHook {
+    fn before<'a>(&'a self, ctx: &'a CommandContext<'a>) -> Pin<Box<dyn Future<Output = Result<HookFlow>> + 'a>> {
+        Box::pin(async move {
+            match ctx.moderation_service.is_banned(&ctx.domain_user.id).await {
+                Ok(Some(ban)) => {
+                    console_warn!("[BanStateHook] User {} is banned (reason: {}), blocking '{}'", ctx.domain_user.id, ban.reason, ctx.command_str);
+                    Ok(HookFlow::ShortCircuit(Response::from_json(&json!({
+                        "method": "sendMessage", "chat_id": ctx.chat_id, "text": "Your account is banned."
+                    }))?))
+                }
+                Ok(None) => {
+                    if ctx.domain_user.state == UserState::Blocked {
+                        if let Err(e) = ctx.moderation_service.clear_stale_block(&ctx.domain_user.id).await {
+                            console_warn!("[BanStateHook] Failed to clear stale block for {}: {}", ctx.domain_user.id, e);
+                        }
+                    }
+                    Ok(HookFlow::Continue)
+                }
+                Err(e) => {
+                    console_warn!("[BanStateHook] Failed to check ban state for {}: {}", ctx.domain_user.id, e);
+                    Ok(HookFlow::Continue)
+                }
+            }
+        })
+    }
+}
+
+/// Enforces an active mute (a chat-restriction short of a full ban) ahead of
+/// every command — real enforcement instead of the placeholder lazy-lift
+/// call `dispatch_command` used to make and then discard the result of.
+/// Lazily lifts any mute whose `unrestrict_at` has already passed (Workers
+/// have no background timer to do that on its own), then short-circuits if
+/// a mute is still active. Runs after `BanStateHook` so a banned user sees
+/// "banned" rather than a mute message.
+pub struct MuteStateHook;
+
+impl CommandHook for MuteStateHook {
+    fn before<'a>(&'a self, ctx: &'a CommandContext<'a>) -> Pin<Box<dyn Future<Output = Result<HookFlow>> + 'a>> {
+        Box::pin(async move {
+            match ctx.moderation_service.check_and_lift_mute(&ctx.domain_user.id).await {
+                Ok(Some(mute)) => {
+                    console_warn!("[MuteStateHook] User {} is muted until {}, blocking '{}'", ctx.domain_user.id, mute.unrestrict_at, ctx.command_str);
+                    Ok(HookFlow::ShortCircuit(Response::from_json(&json!({
+                        "method": "sendMessage", "chat_id": ctx.chat_id,
+                        "text": format!("You're muted until {}.", mute.unrestrict_at)
+                    }))?))
+                }
+                Ok(None) => Ok(HookFlow::Continue),
+                Err(e) => {
+                    console_warn!("[MuteStateHook] Failed to check mute state for {}: {}", ctx.domain_user.id, e);
+                    Ok(HookFlow::Continue)
+                }
+            }
+        })
+    }
+}
+
+/// Runs `RBACService::check_permission` for `command_str`, replacing the
+/// inline checks `dispatch_command` and `handle_start_command` used to
+/// duplicate.
+pub struct RbacHook;
+
+impl CommandHook for RbacHook {
+    fn before<'a>(&'a self, ctx: &'a CommandContext<'a>) -> Pin<Box<dyn Future<Output = Result<HookFlow>> + 'a>> {
+        Box::pin(async move {
+            let scope = ctx.chat_id.to_string();
+            if ctx.rbac_service.check_permission(&ctx.domain_user.id, &ctx.domain_user.roles, ctx.command_str, Some(&scope)).await? {
+                return Ok(HookFlow::Continue);
+            }
+
+            console_warn!("[RbacHook] User {} (roles: {:?}) DENIED for '{}'", ctx.domain_user.id, ctx.domain_user.roles, ctx.command_str);
+            Ok(HookFlow::ShortCircuit(Response::from_json(&json!({
+                "method": "sendMessage", "chat_id": ctx.chat_id, "text": "You don't have permission for that."
+            }))?))
+        })
+    }
+}
+
+/// Records the interaction once the handler has produced a response,
+/// replacing the re-fetch-and-record calls `main` used to make after every
+/// command branch.
+pub struct InteractionRecorderHook;
+
+impl CommandHook for InteractionRecorderHook {
+    fn before<'a>(&'a self, _ctx: &'a CommandContext<'a>) -> Pin<Box<dyn Future<Output = Result<HookFlow>> + 'a>> {
+        Box::pin(async { Ok(HookFlow::Continue) })
+    }
+
+    fn after<'a>(&'a self, ctx: &'a CommandContext<'a>, _response: &'a Response) -> Pin<Box<dyn Future<Output = Result<()>> + 'a>> {
+        Box::pin(async move {
+            if let Err(e) = ctx.user_service.record_user_interaction(&ctx.domain_user.id).await {
+                console_warn!("[InteractionRecorderHook] Failed to record interaction for {}: {}", ctx.domain_user.id, e);
+            }
+            Ok(())
+        })
+    }
+}
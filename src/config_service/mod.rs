@@ -1,38 +1,270 @@
 // src/config_service/mod.rs
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
 use serde::{Deserialize, Serialize};
 use worker::{Env, Result, kv::KvStore, console_log, console_warn, console_error};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// A single feature flag's behavior: plain on/off, or a gradual percentage
+/// rollout. `#[serde(untagged)]` lets the KV document write either a bare
+/// `true`/`false` or `{ "percentage": 10, "salt": "..." }` for the same key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum FlagRule {
+    Bool(bool),
+    Rollout { percentage: u8, salt: String },
+}
+
+/// Named feature flags loaded from KV as a `HashMap<String, FlagRule>`
+/// instead of a fixed set of booleans, so a flag can be added or retired
+/// without a code change. `enable_new_matching_algorithm` and
+/// `enable_real_time_chat` are still resolved by name for backward
+/// compatibility; they're just no longer the only flags that can exist.
+#[derive(Debug, Clone, PartialEq)]
 pub struct FeatureFlags {
-    pub enable_new_matching_algorithm: bool,
-    pub enable_real_time_chat: bool,
+    rules: HashMap<String, FlagRule>,
 }
 
-impl Default for FeatureFlags {
-    fn default() -> Self {
-        Self {
-            enable_new_matching_algorithm: false,
-            enable_real_time_chat: false,
+impl FeatureFlags {
+    /// The only names the bot hard-codes a default for; any other flag name
+    /// only exists once the KV document sets it.
+    const LEGACY_FLAG_DEFAULTS: &'static [(&'static str, bool)] = &[
+        ("enable_new_matching_algorithm", false),
+        ("enable_real_time_chat", false),
+    ];
+
+    fn defaults() -> HashMap<String, FlagRule> {
+        Self::LEGACY_FLAG_DEFAULTS.iter().map(|(name, default)| (name.to_string(), FlagRule::Bool(*default))).collect()
+    }
+
+    /// Evaluates `flag` for `telegram_id`. A `Bool` rule returns its value
+    /// directly; a `Rollout` rule hashes `salt + ":" + telegram_id` with
+    /// FNV-1a and buckets the result into `hash % 100`, enabled once that
+    /// bucket is below `percentage`. The bucket depends only on the flag's
+    /// own salt and the user's id, so a given user stays in or out of a
+    /// rollout across requests and worker restarts, and raising
+    /// `percentage` only ever adds users, never removes any. An unrecognized
+    /// flag name is always disabled.
+    pub fn is_enabled(&self, flag: &str, telegram_id: i64) -> bool {
+        match self.rules.get(flag) {
+            Some(FlagRule::Bool(enabled)) => *enabled,
+            Some(FlagRule::Rollout { percentage, salt }) => {
+                let bucket = fnv1a_hash(format!("{}:{}", salt, telegram_id).as_bytes()) % 100;
+                bucket < u64::from(*percentage)
+            }
+            None => false,
         }
     }
 }
 
+/// A fast, non-cryptographic 64-bit FNV-1a hash, used to bucket users for
+/// `FeatureFlags::is_enabled`'s percentage rollouts.
+fn fnv1a_hash(bytes: &[u8]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash = FNV_OFFSET_BASIS;
+    for &byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// One cached `FeatureFlags` snapshot plus when it was fetched, checked
+/// against the configured TTL by `ConfigService::load_feature_flags`.
+struct CachedFeatureFlags {
+    flags: FeatureFlags,
+    fetched_at: DateTime<Utc>,
+}
+
+/// Caches the last-fetched `FeatureFlags` for the lifetime of the Worker
+/// isolate, the same lifetime `COMMANDS_REGISTERED` in `lib.rs` already
+/// relies on, so a request doesn't pay a `FEATURE_FLAGS_KV` round-trip every
+/// time. `Mutex` rather than `RefCell` only because a `static` requires
+/// `Sync`; Workers is single-threaded per isolate, so the lock is never
+/// actually contended.
+static FEATURE_FLAGS_CACHE: Mutex<Option<CachedFeatureFlags>> = Mutex::new(None);
+
+/// Default TTL for the `FeatureFlags` cache, overridable via
+/// `FEATURE_FLAGS_CACHE_TTL_SECONDS` using the same human-duration syntax
+/// `parse_duration_seconds` already gives `SESSION_TIMEOUT_MINUTES` (e.g.
+/// `30s`, `2m`).
+const DEFAULT_FEATURE_FLAGS_CACHE_TTL_SECONDS: i64 = 60;
+
+/// The deployment tier `ENVIRONMENT` selects, replacing a free-form `String`
+/// (where `prod`/`production`/`Prod` could silently diverge) with a type the
+/// rest of the crate can `match` on. `FromStr` accepts common aliases
+/// case-insensitively; `get_environment_config` falls back to `Dev` and
+/// warns on anything else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    Dev,
+    Staging,
+    Production,
+}
+
+impl Environment {
+    /// The `(log_level, session_timeout_seconds)` profile this tier starts
+    /// from before `LOG_LEVEL`/`SESSION_TIMEOUT_MINUTES` env vars can still
+    /// override either field individually.
+    fn default_profile(&self) -> (&'static str, i64) {
+        match self {
+            Environment::Dev => ("DEBUG", 60 * 60),
+            Environment::Staging => ("INFO", 30 * 60),
+            Environment::Production => ("WARN", 15 * 60),
+        }
+    }
+}
+
+impl std::str::FromStr for Environment {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "dev" | "development" => Ok(Environment::Dev),
+            "staging" | "stage" => Ok(Environment::Staging),
+            "prod" | "production" => Ok(Environment::Production),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Environment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Environment::Dev => "dev",
+            Environment::Staging => "staging",
+            Environment::Production => "production",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl Serialize for Environment {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Environment {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|_| serde::de::Error::custom(format!("unknown environment '{}'", s)))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EnvironmentConfig {
     pub log_level: String,
-    pub environment: String,
-    pub session_timeout_minutes: u32, // New field
+    pub environment: Environment,
+    /// Total length of a session before `SessionTimeoutHook` requires
+    /// re-`/start`, parsed from `SESSION_TIMEOUT_MINUTES` by
+    /// `parse_duration_seconds`. Stored in seconds so the same field can be
+    /// handed to either `chrono::Duration::seconds` or
+    /// `std::time::Duration::from_secs` at the call site.
+    pub session_timeout_seconds: i64,
+    /// Weight given to interest-tag overlap in `matching_service`'s
+    /// compatibility score. Together with `match_proximity_weight` these
+    /// need not sum to 1.0; they're just relative weights.
+    pub match_interest_weight: f64,
+    /// Weight given to geographic proximity in `matching_service`'s
+    /// compatibility score.
+    pub match_proximity_weight: f64,
+    /// Which `formatter::Formatter` renders bot messages: `"MarkdownV2"` or
+    /// `"HTML"`. See `formatter::formatter_for`.
+    pub message_parse_mode: String,
+}
+
+impl EnvironmentConfig {
+    /// Builds the default config for `environment`, before any env-var
+    /// overrides are applied.
+    fn for_environment(environment: Environment) -> Self {
+        let (log_level, session_timeout_seconds) = environment.default_profile();
+        Self {
+            log_level: log_level.to_string(),
+            environment,
+            session_timeout_seconds,
+            match_interest_weight: 0.6,
+            match_proximity_weight: 0.4,
+            message_parse_mode: "MarkdownV2".to_string(),
+        }
+    }
 }
 
 impl Default for EnvironmentConfig {
     fn default() -> Self {
-        Self {
-            log_level: "INFO".to_string(),
-            environment: "dev".to_string(),
-            session_timeout_minutes: 30, // Default to 30 minutes
+        Self::for_environment(Environment::Dev)
+    }
+}
+
+/// Parses a human duration like `30m`, `1h30m`, `90s`, `2h`, or `1w` into a
+/// total number of seconds, scanning left to right and accumulating digit
+/// runs. Each unit suffix (`s`/`m`/`h`/`d`/`w`) multiplies its accumulated
+/// number by 1/60/3600/86400/604800 seconds and adds it to the running
+/// total. A bare number with no suffix at all (e.g. `45`) keeps the legacy
+/// "minutes" meaning existing `SESSION_TIMEOUT_MINUTES` values relied on.
+/// Returns `None` for an unknown suffix, empty input, or a trailing number
+/// left over with no unit after at least one unit has already been applied
+/// (e.g. `1h30`, ambiguous between seconds and minutes).
+pub(crate) fn parse_duration_seconds(input: &str) -> Option<i64> {
+    let input = input.trim();
+    if input.is_empty() {
+        return None;
+    }
+
+    let mut total_seconds: i64 = 0;
+    let mut digits = String::new();
+    let mut saw_unit = false;
+
+    for c in input.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        let amount: i64 = digits.parse().ok()?;
+        digits.clear();
+
+        let multiplier = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 3_600,
+            'd' => 86_400,
+            'w' => 604_800,
+            _ => return None,
+        };
+        total_seconds += amount * multiplier;
+        saw_unit = true;
+    }
+
+    if !digits.is_empty() {
+        if saw_unit {
+            return None;
         }
+        total_seconds = digits.parse::<i64>().ok()? * 60;
     }
+
+    Some(total_seconds)
 }
+
+/// The fully-resolved configuration `ConfigService::load` produces, bundling
+/// `EnvironmentConfig` (env-var only) with `FeatureFlags` (now layered
+/// default/KV/env) so callers have one value to thread through instead of
+/// loading each separately.
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    pub environment: EnvironmentConfig,
+    pub feature_flags: FeatureFlags,
+}
+
 pub struct ConfigService;
 
 impl ConfigService {
@@ -40,69 +272,337 @@ impl ConfigService {
         Self
     }
 
-    pub async fn get_feature_flags(&self, env: &Env) -> Result<FeatureFlags> {
-        console_log!("[ConfigService] Attempting to load feature flags from KV 'FEATURE_FLAGS_KV'.");
+    /// Loads the full application configuration with explicit layer
+    /// precedence, lowest to highest: `FeatureFlags::defaults()` (the two
+    /// legacy named flags, off), the `FEATURE_FLAGS_KV` JSON document
+    /// (overwriting/adding whichever flag names it sets), then a
+    /// `FF_<NAME>` env-var bool override per flag on top of that — so an
+    /// operator can flip a flag on via e.g. `FF_ENABLE_REAL_TIME_CHAT=true`
+    /// without touching the KV document, for a legacy flag or a brand new
+    /// dynamic one alike. `EnvironmentConfig` has no KV layer and loads the
+    /// same way `get_environment_config` always has.
+    pub async fn load(&self, env: &Env) -> AppConfig {
+        let environment = self.get_environment_config(env).await.unwrap_or_else(|e| {
+            console_error!("[ConfigService] Critical error loading env config: {}. Using defaults.", e);
+            EnvironmentConfig::default()
+        });
+        let feature_flags = self.load_feature_flags(env).await;
+
+        AppConfig { environment, feature_flags }
+    }
+
+    /// Returns the cached `FeatureFlags` while the cache is fresher than
+    /// `FEATURE_FLAGS_CACHE_TTL_SECONDS` (default
+    /// `DEFAULT_FEATURE_FLAGS_CACHE_TTL_SECONDS`), avoiding a KV round-trip
+    /// on every request; otherwise refreshes from KV and re-populates the
+    /// cache. On a refresh error, serves the last known-good cached value
+    /// rather than silently falling back to defaults, since a transient KV
+    /// error shouldn't look identical to an operator actually clearing the
+    /// flags document.
+    async fn load_feature_flags(&self, env: &Env) -> FeatureFlags {
+        let ttl_seconds = env.var("FEATURE_FLAGS_CACHE_TTL_SECONDS")
+            .ok()
+            .and_then(|v| parse_duration_seconds(&v.to_string()))
+            .unwrap_or(DEFAULT_FEATURE_FLAGS_CACHE_TTL_SECONDS);
+
+        if let Some(flags) = Self::cached_feature_flags_if_fresh(Duration::seconds(ttl_seconds)) {
+            return flags;
+        }
 
-        let store_result = env.kv("FEATURE_FLAGS_KV");
-        let store = match store_result {
-            Ok(s) => s,
+        match self.refresh_feature_flags(env).await {
+            Ok(flags) => flags,
             Err(e) => {
-                console_error!("[ConfigService] Failed to bind to KV 'FEATURE_FLAGS_KV': {}. Using default flags.", e);
-                return Ok(FeatureFlags::default());
+                console_warn!("[ConfigService] Failed to refresh feature flags from KV: {}. Serving last known-good value.", e);
+                Self::cached_feature_flags_ignoring_ttl().unwrap_or_else(|| {
+                    console_warn!("[ConfigService] No cached feature flags to fall back on yet; using defaults.");
+                    FeatureFlags { rules: FeatureFlags::defaults() }
+                })
             }
-        };
+        }
+    }
 
-        match store.get("current_flags").json::<FeatureFlags>().await {
-            Ok(Some(flags)) => {
-                console_log!("[ConfigService] Successfully loaded feature flags from KV: {:?}", flags);
-                Ok(flags)
-            },
-            Ok(None) => {
-                console_warn!("[ConfigService] No 'current_flags' key in KV 'FEATURE_FLAGS_KV'. Using default flags.");
-                Ok(FeatureFlags::default())
-            },
-            Err(e) => {
-                console_error!("[ConfigService] Error reading/parsing flags from KV 'FEATURE_FLAGS_KV': {}. Using default flags.", e);
-                Ok(FeatureFlags::default())
+    /// Re-reads KV and env overrides unconditionally and stores the result
+    /// in `FEATURE_FLAGS_CACHE` with a fresh `fetched_at`, the same work
+    /// `/admin` commands trigger via `invalidate()` plus the next request.
+    async fn refresh_feature_flags(&self, env: &Env) -> Result<FeatureFlags> {
+        let mut rules = FeatureFlags::defaults();
+        for (name, rule) in self.get_feature_flags_from_kv(env).await? {
+            rules.insert(name, rule);
+        }
+
+        let names: Vec<String> = rules.keys().cloned().collect();
+        for name in &names {
+            let env_var = format!("FF_{}", name.to_uppercase());
+            let env_value = env.var(&env_var).ok().map(|v| v.to_string());
+            Self::apply_env_override(&mut rules, name, &env_var, env_value.as_deref());
+        }
+
+        let flags = FeatureFlags { rules };
+        *FEATURE_FLAGS_CACHE.lock().unwrap() = Some(CachedFeatureFlags { flags: flags.clone(), fetched_at: Utc::now() });
+        Ok(flags)
+    }
+
+    /// Reads and parses `FEATURE_FLAGS_KV`'s `current_flags` key as a
+    /// `HashMap<String, FlagRule>`. A missing `current_flags` key is a
+    /// legitimate "no overrides configured" state and returns an empty map;
+    /// a KV binding or parse error propagates so `load_feature_flags` can
+    /// tell it apart from that and fall back to the cache instead.
+    async fn get_feature_flags_from_kv(&self, env: &Env) -> Result<HashMap<String, FlagRule>> {
+        let store = env.kv("FEATURE_FLAGS_KV")?;
+
+        match store.get("current_flags").json::<HashMap<String, FlagRule>>().await? {
+            Some(rules) => Ok(rules),
+            None => {
+                console_warn!("[ConfigService] No 'current_flags' key in KV 'FEATURE_FLAGS_KV'. No KV overrides.");
+                Ok(HashMap::new())
+            }
+        }
+    }
+
+    /// Returns the cached flags if one exists and is younger than `ttl`.
+    fn cached_feature_flags_if_fresh(ttl: Duration) -> Option<FeatureFlags> {
+        let cache = FEATURE_FLAGS_CACHE.lock().unwrap();
+        cache.as_ref()
+            .filter(|cached| Utc::now().signed_duration_since(cached.fetched_at) < ttl)
+            .map(|cached| cached.flags.clone())
+    }
+
+    /// Returns the cached flags regardless of age, for the "refresh failed,
+    /// serve stale" fallback.
+    fn cached_feature_flags_ignoring_ttl() -> Option<FeatureFlags> {
+        FEATURE_FLAGS_CACHE.lock().unwrap().as_ref().map(|cached| cached.flags.clone())
+    }
+
+    /// Drops the cached `FeatureFlags` so the next `load`/`load_feature_flags`
+    /// call re-reads KV immediately, regardless of TTL. Intended for an admin
+    /// command to call right after editing the flags document.
+    pub fn invalidate(&self) {
+        *FEATURE_FLAGS_CACHE.lock().unwrap() = None;
+    }
+
+    /// Overrides `name`'s rule in `rules` with a plain bool parsed from
+    /// `env_var_value`, if present and valid; logs which env var produced
+    /// the override so a surprising flag value is debuggable. A pure
+    /// function aside from the env lookup itself (done by the caller), so
+    /// the override logic is unit-testable without a `worker::Env`.
+    fn apply_env_override(rules: &mut HashMap<String, FlagRule>, name: &str, env_var: &str, env_var_value: Option<&str>) {
+        let Some(val) = env_var_value else { return };
+        match val.parse::<bool>() {
+            Ok(b) => {
+                console_log!("[ConfigService] {} overridden to {} via {}.", name, b, env_var);
+                rules.insert(name.to_string(), FlagRule::Bool(b));
             }
+            Err(_) => console_warn!("[ConfigService] {} is not a valid bool ('{}'). Ignoring.", env_var, val),
         }
     }
 
     pub async fn get_environment_config(&self, env: &Env) -> Result<EnvironmentConfig> {
         console_log!("[ConfigService] Loading environment config.");
         let environment = match env.var("ENVIRONMENT") {
-            Ok(var) => var.to_string(),
+            Ok(var) => match var.to_string().parse::<Environment>() {
+                Ok(parsed) => parsed,
+                Err(_) => {
+                    console_warn!("[ConfigService] ENVIRONMENT ('{}') isn't a recognized tier (dev/staging/production). Falling back to {}.", var.to_string(), Environment::Dev);
+                    Environment::Dev
+                }
+            },
             Err(_) => {
-                console_warn!("[ConfigService] ENVIRONMENT variable not set, using default '{}'.", EnvironmentConfig::default().environment);
-                EnvironmentConfig::default().environment
+                console_warn!("[ConfigService] ENVIRONMENT variable not set, using default '{}'.", Environment::Dev);
+                Environment::Dev
             }
         };
+        let (tier_log_level, tier_session_timeout_seconds) = environment.default_profile();
+
         let log_level = match env.var("LOG_LEVEL") {
             Ok(var) => var.to_string(),
             Err(_) => {
-                console_warn!("[ConfigService] LOG_LEVEL variable not set, using default '{}'.", EnvironmentConfig::default().log_level);
-                EnvironmentConfig::default().log_level
+                console_warn!("[ConfigService] LOG_LEVEL variable not set, using {}'s default '{}'.", environment, tier_log_level);
+                tier_log_level.to_string()
             }
         };
 
-        let session_timeout_minutes = match env.var("SESSION_TIMEOUT_MINUTES") {
-            Ok(var_str) => match var_str.to_string().parse::<u32>() {
+        let session_timeout_seconds = match env.var("SESSION_TIMEOUT_MINUTES") {
+            Ok(var_str) => match parse_duration_seconds(&var_str.to_string()) {
+                Some(secs) => secs,
+                None => {
+                    console_warn!("[ConfigService] SESSION_TIMEOUT_MINUTES ('{}') isn't a valid duration (e.g. '30m', '1h30m', '90s'). Using {}'s default {}s.", var_str.to_string(), environment, tier_session_timeout_seconds);
+                    tier_session_timeout_seconds
+                }
+            },
+            Err(_) => {
+                console_warn!("[ConfigService] SESSION_TIMEOUT_MINUTES variable not set, using {}'s default {}s.", environment, tier_session_timeout_seconds);
+                tier_session_timeout_seconds
+            }
+        };
+
+        let match_interest_weight = match env.var("MATCH_INTEREST_WEIGHT") {
+            Ok(var_str) => match var_str.to_string().parse::<f64>() {
                 Ok(val) => val,
                 Err(_) => {
-                    console_warn!("[ConfigService] SESSION_TIMEOUT_MINUTES is not a valid u32 ('{}'). Using default {}.", var_str.to_string(), EnvironmentConfig::default().session_timeout_minutes);
-                    EnvironmentConfig::default().session_timeout_minutes
+                    console_warn!("[ConfigService] MATCH_INTEREST_WEIGHT is not a valid f64 ('{}'). Using default {}.", var_str.to_string(), EnvironmentConfig::default().match_interest_weight);
+                    EnvironmentConfig::default().match_interest_weight
                 }
             },
             Err(_) => {
-                console_warn!("[ConfigService] SESSION_TIMEOUT_MINUTES variable not set, using default {}.", EnvironmentConfig::default().session_timeout_minutes);
-                EnvironmentConfig::default().session_timeout_minutes
+                console_warn!("[ConfigService] MATCH_INTEREST_WEIGHT variable not set, using default {}.", EnvironmentConfig::default().match_interest_weight);
+                EnvironmentConfig::default().match_interest_weight
+            }
+        };
+
+        let match_proximity_weight = match env.var("MATCH_PROXIMITY_WEIGHT") {
+            Ok(var_str) => match var_str.to_string().parse::<f64>() {
+                Ok(val) => val,
+                Err(_) => {
+                    console_warn!("[ConfigService] MATCH_PROXIMITY_WEIGHT is not a valid f64 ('{}'). Using default {}.", var_str.to_string(), EnvironmentConfig::default().match_proximity_weight);
+                    EnvironmentConfig::default().match_proximity_weight
+                }
+            },
+            Err(_) => {
+                console_warn!("[ConfigService] MATCH_PROXIMITY_WEIGHT variable not set, using default {}.", EnvironmentConfig::default().match_proximity_weight);
+                EnvironmentConfig::default().match_proximity_weight
+            }
+        };
+
+        let message_parse_mode = match env.var("MESSAGE_PARSE_MODE") {
+            Ok(var) => var.to_string(),
+            Err(_) => {
+                console_warn!("[ConfigService] MESSAGE_PARSE_MODE variable not set, using default '{}'.", EnvironmentConfig::default().message_parse_mode);
+                EnvironmentConfig::default().message_parse_mode
             }
         };
 
         Ok(EnvironmentConfig {
             environment,
             log_level,
-            session_timeout_minutes,
+            session_timeout_seconds,
+            match_interest_weight,
+            match_proximity_weight,
+            message_parse_mode,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_environment_from_str_accepts_common_aliases_case_insensitively() {
+        for alias in ["dev", "Dev", "DEVELOPMENT", "development"] {
+            assert_eq!(alias.parse::<Environment>(), Ok(Environment::Dev));
+        }
+        for alias in ["staging", "Staging", "STAGE", "stage"] {
+            assert_eq!(alias.parse::<Environment>(), Ok(Environment::Staging));
+        }
+        for alias in ["production", "Production", "PROD", "prod"] {
+            assert_eq!(alias.parse::<Environment>(), Ok(Environment::Production));
+        }
+    }
+
+    #[test]
+    fn test_environment_from_str_rejects_unknown_values() {
+        assert_eq!("whatever".parse::<Environment>(), Err(()));
+        assert_eq!("".parse::<Environment>(), Err(()));
+    }
+
+    #[test]
+    fn test_environment_default_profile_differs_per_tier() {
+        let (dev_log, dev_timeout) = Environment::Dev.default_profile();
+        let (prod_log, prod_timeout) = Environment::Production.default_profile();
+        assert_ne!(dev_log, prod_log);
+        assert!(prod_timeout < dev_timeout, "production should default to a stricter session timeout than dev");
+    }
+
+    #[test]
+    fn test_parse_duration_seconds_single_unit() {
+        assert_eq!(parse_duration_seconds("90s"), Some(90));
+        assert_eq!(parse_duration_seconds("30m"), Some(30 * 60));
+        assert_eq!(parse_duration_seconds("2h"), Some(2 * 3_600));
+        assert_eq!(parse_duration_seconds("1d"), Some(86_400));
+        assert_eq!(parse_duration_seconds("1w"), Some(604_800));
+    }
+
+    #[test]
+    fn test_parse_duration_seconds_combines_units() {
+        assert_eq!(parse_duration_seconds("1h30m"), Some(3_600 + 30 * 60));
+    }
+
+    #[test]
+    fn test_parse_duration_seconds_bare_number_is_legacy_minutes() {
+        assert_eq!(parse_duration_seconds("45"), Some(45 * 60));
+    }
+
+    #[test]
+    fn test_parse_duration_seconds_rejects_invalid_input() {
+        assert_eq!(parse_duration_seconds(""), None);
+        assert_eq!(parse_duration_seconds("30x"), None);
+        assert_eq!(parse_duration_seconds("1h30"), None, "a trailing number with no unit after another unit is ambiguous");
+    }
+
+    #[test]
+    fn test_is_enabled_bool_rule() {
+        let flags = FeatureFlags { rules: HashMap::from([("enable_real_time_chat".to_string(), FlagRule::Bool(true))]) };
+        assert!(flags.is_enabled("enable_real_time_chat", 12345));
+    }
+
+    #[test]
+    fn test_is_enabled_unknown_flag_is_disabled() {
+        let flags = FeatureFlags { rules: HashMap::new() };
+        assert!(!flags.is_enabled("some_flag_nobody_registered", 12345));
+    }
+
+    #[test]
+    fn test_is_enabled_rollout_is_deterministic_per_user() {
+        let flags = FeatureFlags {
+            rules: HashMap::from([("new_chat_ui".to_string(), FlagRule::Rollout { percentage: 50, salt: "new_chat_ui".to_string() })]),
+        };
+        for telegram_id in [1i64, 2, 3, 42, 1_000_000] {
+            let first = flags.is_enabled("new_chat_ui", telegram_id);
+            let second = flags.is_enabled("new_chat_ui", telegram_id);
+            assert_eq!(first, second, "the same user must bucket the same way every time");
+        }
+    }
+
+    #[test]
+    fn test_is_enabled_rollout_bounds() {
+        let always_off = FeatureFlags {
+            rules: HashMap::from([("f".to_string(), FlagRule::Rollout { percentage: 0, salt: "s".to_string() })]),
+        };
+        let always_on = FeatureFlags {
+            rules: HashMap::from([("f".to_string(), FlagRule::Rollout { percentage: 100, salt: "s".to_string() })]),
+        };
+        for telegram_id in [1i64, 2, 3, 42, 1_000_000] {
+            assert!(!always_off.is_enabled("f", telegram_id));
+            assert!(always_on.is_enabled("f", telegram_id));
+        }
+    }
+
+    #[test]
+    fn test_is_enabled_raising_percentage_only_adds_users() {
+        let salt = "gradual_rollout";
+        let at_50 = FeatureFlags { rules: HashMap::from([("f".to_string(), FlagRule::Rollout { percentage: 50, salt: salt.to_string() })]) };
+        let at_80 = FeatureFlags { rules: HashMap::from([("f".to_string(), FlagRule::Rollout { percentage: 80, salt: salt.to_string() })]) };
+        for telegram_id in 0..200i64 {
+            if at_50.is_enabled("f", telegram_id) {
+                assert!(at_80.is_enabled("f", telegram_id), "raising percentage dropped a previously-enabled user {}", telegram_id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_env_override_parses_and_overrides() {
+        let mut rules = FeatureFlags::defaults();
+        ConfigService::apply_env_override(&mut rules, "enable_real_time_chat", "FF_ENABLE_REAL_TIME_CHAT", Some("true"));
+        assert_eq!(rules.get("enable_real_time_chat"), Some(&FlagRule::Bool(true)));
+    }
+
+    #[test]
+    fn test_apply_env_override_ignores_missing_or_invalid_value() {
+        let mut rules = FeatureFlags::defaults();
+        ConfigService::apply_env_override(&mut rules, "enable_real_time_chat", "FF_ENABLE_REAL_TIME_CHAT", None);
+        assert_eq!(rules.get("enable_real_time_chat"), Some(&FlagRule::Bool(false)));
+
+        ConfigService::apply_env_override(&mut rules, "enable_real_time_chat", "FF_ENABLE_REAL_TIME_CHAT", Some("not-a-bool"));
+        assert_eq!(rules.get("enable_real_time_chat"), Some(&FlagRule::Bool(false)));
+    }
+}
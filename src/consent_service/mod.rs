@@ -0,0 +1,95 @@
+// src/consent_service/mod.rs
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use worker::{D1Database, Env, Result, console_log};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ConsentType {
+    Match,
+    DirectMessage,
+    ShareLocation,
+    ShareMedia,
+}
+
+impl ConsentType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ConsentType::Match => "Match",
+            ConsentType::DirectMessage => "DirectMessage",
+            ConsentType::ShareLocation => "ShareLocation",
+            ConsentType::ShareMedia => "ShareMedia",
+        }
+    }
+}
+
+/// One row of the `consents` table: `granter_user_id` has allowed
+/// `grantee_user_id` to do `consent_type` with them, until (if ever) revoked.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Consent {
+    pub granter_user_id: String,
+    pub grantee_user_id: String,
+    pub consent_type: String,
+    pub granted_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+/// Gates matching/messaging/data-sharing behind explicit, revocable consent
+/// between two users, required before they can be connected or see each
+/// other's sensitive fields.
+pub struct ConsentService {
+    db: D1Database,
+}
+
+impl ConsentService {
+    pub fn new(env: &Env) -> Result<Self> {
+        let db = env.d1("DB")?;
+        Ok(Self { db })
+    }
+
+    /// Records that `granter` consents to `consent_type` from `grantee`. Grants
+    /// are append-only; a prior revocation is superseded by granting again.
+    pub async fn grant(&self, granter_user_id: &str, grantee_user_id: &str, consent_type: ConsentType) -> Result<()> {
+        console_log!("[ConsentService] {} grants {:?} to {}", granter_user_id, consent_type, grantee_user_id);
+        let now = Utc::now();
+        self.db.prepare(
+            "INSERT INTO consents (granter_user_id, grantee_user_id, consent_type, granted_at, revoked_at) \
+             VALUES (?1, ?2, ?3, ?4, NULL)"
+        )
+        .bind(&[granter_user_id.into(), grantee_user_id.into(), consent_type.as_str().into(), now.to_rfc3339().into()])?
+        .run().await?;
+        Ok(())
+    }
+
+    /// Marks the most recent active grant from `granter` to `grantee` for
+    /// `consent_type` as revoked.
+    pub async fn revoke(&self, granter_user_id: &str, grantee_user_id: &str, consent_type: ConsentType) -> Result<()> {
+        console_log!("[ConsentService] {} revokes {:?} from {}", granter_user_id, consent_type, grantee_user_id);
+        let now = Utc::now();
+        self.db.prepare(
+            "UPDATE consents SET revoked_at = ?1 \
+             WHERE granter_user_id = ?2 AND grantee_user_id = ?3 AND consent_type = ?4 AND revoked_at IS NULL"
+        )
+        .bind(&[now.to_rfc3339().into(), granter_user_id.into(), grantee_user_id.into(), consent_type.as_str().into()])?
+        .run().await?;
+        Ok(())
+    }
+
+    /// True if `granter` has an active (non-revoked) grant of `consent_type` to
+    /// `grantee`. A revoked row is treated as if it never existed.
+    pub async fn has_consent(&self, granter_user_id: &str, grantee_user_id: &str, consent_type: ConsentType) -> Result<bool> {
+        let row = self.db.prepare(
+            "SELECT 1 as present FROM consents \
+             WHERE granter_user_id = ?1 AND grantee_user_id = ?2 AND consent_type = ?3 AND revoked_at IS NULL \
+             LIMIT 1"
+        )
+        .bind(&[granter_user_id.into(), grantee_user_id.into(), consent_type.as_str().into()])?
+        .first::<std::collections::HashMap<String, i64>>(None).await?;
+        Ok(row.is_some())
+    }
+
+    /// True if both users have granted `consent_type` to each other.
+    pub async fn is_mutual(&self, user_a: &str, user_b: &str, consent_type: ConsentType) -> Result<bool> {
+        Ok(self.has_consent(user_a, user_b, consent_type.clone()).await?
+            && self.has_consent(user_b, user_a, consent_type).await?)
+    }
+}
@@ -0,0 +1,101 @@
+// src/formatter/mod.rs
+
+/// Builds a Telegram message body in one markup language, and names the
+/// `parse_mode` Telegram needs to render it. `format_user_profile_view` (and
+/// any future formatted message) is written once against this trait instead
+/// of concatenating markup strings by hand, so the same content can render
+/// as MarkdownV2 or HTML depending on what the caller passes in.
+pub trait Formatter {
+    /// Escapes `text` so it renders as literal content instead of being
+    /// parsed as markup.
+    fn escape(&self, text: &str) -> String;
+    fn bold(&self, text: &str) -> String;
+    fn italic(&self, text: &str) -> String;
+    fn code(&self, text: &str) -> String;
+    fn code_block(&self, text: &str) -> String;
+    /// The Telegram `parse_mode` value that matches this formatter's markup.
+    fn parse_mode(&self) -> &'static str;
+}
+
+/// Telegram's MarkdownV2, whose reserved characters must be escaped with a
+/// leading backslash wherever they appear in dynamic content:
+/// `https://core.telegram.org/bots/api#markdownv2-style`.
+pub struct MarkdownV2Formatter;
+
+impl Formatter for MarkdownV2Formatter {
+    fn escape(&self, text: &str) -> String {
+        const RESERVED: &[char] = &[
+            '_', '*', '[', ']', '(', ')', '~', '`', '>', '#', '+', '-', '=', '|', '{', '}', '.', '!',
+        ];
+        let mut escaped = String::with_capacity(text.len());
+        for c in text.chars() {
+            if RESERVED.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
+    fn bold(&self, text: &str) -> String {
+        format!("*{}*", text)
+    }
+
+    fn italic(&self, text: &str) -> String {
+        format!("_{}_", text)
+    }
+
+    fn code(&self, text: &str) -> String {
+        format!("`{}`", text)
+    }
+
+    fn code_block(&self, text: &str) -> String {
+        format!("```\n{}\n```", text)
+    }
+
+    fn parse_mode(&self) -> &'static str {
+        "MarkdownV2"
+    }
+}
+
+/// Telegram's HTML parse mode, an alternative to MarkdownV2 for content
+/// where hand-escaping markdown is fragile (e.g. text with a lot of
+/// punctuation). Only `&`, `<`, and `>` need escaping.
+pub struct HtmlFormatter;
+
+impl Formatter for HtmlFormatter {
+    fn escape(&self, text: &str) -> String {
+        text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+    }
+
+    fn bold(&self, text: &str) -> String {
+        format!("<b>{}</b>", text)
+    }
+
+    fn italic(&self, text: &str) -> String {
+        format!("<i>{}</i>", text)
+    }
+
+    fn code(&self, text: &str) -> String {
+        format!("<code>{}</code>", text)
+    }
+
+    fn code_block(&self, text: &str) -> String {
+        format!("<pre>{}</pre>", text)
+    }
+
+    fn parse_mode(&self) -> &'static str {
+        "HTML"
+    }
+}
+
+/// Picks the `Formatter` named by `EnvironmentConfig::message_parse_mode`
+/// (`"MarkdownV2"` or `"HTML"`, case-insensitive), falling back to
+/// MarkdownV2 for anything else.
+pub fn formatter_for(parse_mode: &str) -> Box<dyn Formatter> {
+    if parse_mode.eq_ignore_ascii_case("HTML") {
+        Box::new(HtmlFormatter)
+    } else {
+        Box::new(MarkdownV2Formatter)
+    }
+}
@@ -8,29 +8,58 @@ mod communication_service;
 mod analytics_service;
 mod notification_service;
 mod rbac_service;
+mod cleanup_service;
+mod consent_service;
+mod ban_service;
+mod moderation_service;
+mod command_hooks;
+mod formatter;
+mod media_service;
 
 use worker::*;
 use serde::Deserialize;
 use serde_json::json;
 
-use config_service::{ConfigService, EnvironmentConfig};
+use config_service::{AppConfig, ConfigService, EnvironmentConfig};
 use user_service::{UserService, User as DomainUser, UserState};
-use rbac_service::{RBACService, Role as UserRole}; // UserRole alias for clarity
+use rbac_service::{RBACService, Role as UserRole, ChangeResult}; // UserRole alias for clarity
+use moderation_service::ModerationService;
+use matching_service::MatchingService;
+use monitoring_service::{MonitoringService, HealthReport};
+use command_hooks::{CommandContext, HookChain, HookRegistry, SessionTimeoutHook, BanStateHook, MuteStateHook, RbacHook, InteractionRecorderHook};
+use formatter::{Formatter, formatter_for};
 use chrono::Duration;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::OnceLock;
 
 
 // --- Telegram Type Definitions ---
 #[derive(Deserialize, Debug)]
 pub struct TelegramUpdate {
     pub message: Option<TelegramMessage>,
-    // pub callback_query: Option<CallbackQuery>, // For future button interactions
+    pub callback_query: Option<CallbackQuery>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct TelegramMessage {
+    pub message_id: i64,
     pub from: Option<TelegramUser>,
     pub chat: TelegramChat,
     pub text: Option<String>,
+    /// The message this one replies to, if any — used to resolve a
+    /// moderation command's target ("reply to the user you want to /mute").
+    pub reply_to_message: Option<Box<TelegramMessage>>,
+}
+
+/// A tap on an inline keyboard button (e.g. `match:accept:<user_id>`),
+/// delivered as `update.callback_query` instead of `update.message`.
+#[derive(Deserialize, Debug)]
+pub struct CallbackQuery {
+    pub id: String,
+    pub from: TelegramUser,
+    pub message: Option<TelegramMessage>,
+    pub data: String,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -42,34 +71,137 @@ pub struct TelegramUser {
 #[derive(Deserialize, Debug)]
 pub struct TelegramChat {
     pub id: i64,
+    #[serde(rename = "type")]
+    pub chat_type: String,
 }
 // --- End of Telegram Type Definitions ---
 
+/// Escapes MarkdownV2's reserved characters
+/// (`https://core.telegram.org/bots/api#markdownv2-style`) so dynamic
+/// content can't be misread as formatting syntax. Kept as a free function
+/// (rather than only living behind `formatter::MarkdownV2Formatter`) since
+/// a few call sites need to escape a value without building a whole
+/// `Formatter`.
+fn escape_markdown_v2(text: &str) -> String {
+    formatter::MarkdownV2Formatter.escape(text)
+}
+
+/// Renders `/profile`'s body through `formatter`, so the same content can
+/// come out as MarkdownV2 or HTML depending on what the caller picked.
+/// Every dynamic value — including the numeric coordinates, which a
+/// previous hand-rolled version of this function left unescaped — goes
+/// through `formatter.escape` uniformly.
+fn format_user_profile_view(user: &DomainUser, formatter: &dyn Formatter) -> String {
+    let name = user.name.as_deref().map(|n| formatter.escape(n)).unwrap_or_else(|| "Not set".to_string());
+    let age = user.age.map(|a| a.to_string()).unwrap_or_else(|| "Not set".to_string());
+    let gender = user.gender.as_deref().map(|g| formatter.escape(g)).unwrap_or_else(|| "Not set".to_string());
+    let bio = user.bio.as_deref().map(|b| formatter.escape(b)).unwrap_or_else(|| "Not set".to_string());
+
+    let location = match (&user.location_text, user.latitude, user.longitude) {
+        (Some(text), Some(lat), Some(lng)) => Some(format!("{} (Lat: {:.3}, Lon: {:.3})", text, lat, lng)),
+        (Some(text), _, _) => Some(text.clone()),
+        (None, Some(lat), Some(lng)) => Some(format!("Lat: {:.3}, Lon: {:.3}", lat, lng)),
+        (None, _, _) => None,
+    }
+    .map(|s| formatter.escape(&s))
+    .unwrap_or_else(|| "Not set".to_string());
+
+    format!(
+        "{title}\n{sep}\n\
+{name_label}: {name}\n\
+{age_label}: {age}\n\
+{gender_label}: {gender}\n\
+{bio_label}: {bio}\n\
+{location_label}: {location}\n\
+{media_label}: {media_count} items\n\
+Roles: {roles}\n\
+State: {state}\n\
+Joined: {joined}\n\
+Last Interaction: {last_interaction}\n\
+\nTo edit, try: {edit_cmd} {edit_hint}",
+        title = formatter.bold("Your Profile"),
+        sep = formatter.escape(&"-".repeat(15)),
+        name_label = formatter.bold("Name"),
+        age_label = formatter.bold("Age"),
+        gender_label = formatter.bold("Gender"),
+        bio_label = formatter.bold("Bio"),
+        location_label = formatter.bold("Location"),
+        media_label = formatter.bold("Media Items"),
+        media_count = user.media_keys.len(),
+        roles = formatter.code(&format!("{:?}", user.roles)),
+        state = formatter.code(&format!("{:?}", user.state)),
+        joined = formatter.code(&user.created_at.format("%Y-%m-%d %H:%M UTC").to_string()),
+        last_interaction = formatter.code(&user.last_interaction_at.format("%Y-%m-%d %H:%M UTC").to_string()),
+        edit_cmd = formatter.code("/profile edit name"),
+        edit_hint = formatter.escape("(feature coming soon!)"),
+    )
+}
+
 // --- Placeholder Command Handlers ---
-async fn handle_profile_command(_user_service: &UserService, _rbac_service: &RBACService, _env_config: &EnvironmentConfig, user: &DomainUser, chat_id: i64, _args: Vec<&str>) -> Result<Response> {
+async fn handle_profile_command(_user_service: &UserService, _rbac_service: &RBACService, env_config: &EnvironmentConfig, user: &DomainUser, chat_id: i64, _args: Vec<&str>) -> Result<Response> {
     console_log!("[CmdHandler] /profile for user {}", user.id);
+    let formatter = formatter_for(&env_config.message_parse_mode);
     Response::from_json(&json!({
         "method": "sendMessage", "chat_id": chat_id,
-        "text": format!("Placeholder for /profile. Hello, {}!", user.name.as_deref().unwrap_or("User"))
+        "text": format_user_profile_view(user, formatter.as_ref()),
+        "parse_mode": formatter.parse_mode(),
     }))
 }
 
-async fn handle_find_match_command(_user_service: &UserService, _rbac_service: &RBACService, _env_config: &EnvironmentConfig, user: &DomainUser, chat_id: i64, _args: Vec<&str>) -> Result<Response> {
+/// Radius used for `/find_match`'s nearby-user search.
+const FIND_MATCH_RADIUS_KM: f64 = 50.0;
+
+async fn handle_find_match_command(env: &Env, user_service: &UserService, _rbac_service: &RBACService, env_config: &EnvironmentConfig, user: &DomainUser, chat_id: i64, _args: Vec<&str>) -> Result<Response> {
     console_log!("[CmdHandler] /find_match for user {}", user.id);
+
+    let (lat, lng) = match (user.latitude, user.longitude) {
+        (Some(lat), Some(lng)) => (lat, lng),
+        _ => {
+            return Response::from_json(&json!({
+                "method": "sendMessage", "chat_id": chat_id,
+                "text": "Set your location first with /profile to find matches near you."
+            }));
+        }
+    };
+
+    // Bounding-box/radius prefilter assembles the candidate pool; the exact
+    // compatibility scoring and stable-matching happen in MatchingService.
+    let nearby: Vec<DomainUser> = user_service.find_nearby_users(lat, lng, FIND_MATCH_RADIUS_KM, 50).await?
+        .into_iter().map(|(candidate, _)| candidate).collect();
+
+    let matching_service = MatchingService::new(env)?;
+    let Some((candidate, score)) = matching_service.find_match_for(user, nearby, env_config).await? else {
+        return Response::from_json(&json!({
+            "method": "sendMessage", "chat_id": chat_id,
+            "text": "No one nearby right now. Try again later!"
+        }));
+    };
+
+    let inline_keyboard = json!({
+        "inline_keyboard": [[
+            {"text": "❤️ Accept", "callback_data": format!("match:accept:{}", candidate.id)},
+            {"text": "✖️ Pass", "callback_data": format!("match:reject:{}", candidate.id)},
+        ]]
+    });
+
     Response::from_json(&json!({
         "method": "sendMessage", "chat_id": chat_id,
-        "text": "Placeholder for /find_match. Searching for potential matches..."
+        "text": format!(
+            "{} looks like a {:.0}% match. Interested?",
+            candidate.name.as_deref().unwrap_or("Someone"), score.clamp(0.0, 1.0) * 100.0
+        ),
+        "reply_markup": inline_keyboard
     }))
 }
 
-async fn handle_help_command(_user_service: &UserService, rbac_service: &RBACService, _env_config: &EnvironmentConfig, user: &DomainUser, chat_id: i64, _args: Vec<&str>) -> Result<Response> {
+async fn handle_help_command(_user_service: &UserService, _rbac_service: &RBACService, _env_config: &EnvironmentConfig, user: &DomainUser, chat_id: i64, _args: Vec<&str>) -> Result<Response> {
     console_log!("[CmdHandler] /help for user {}", user.id);
-    let mut help_text = "Available commands:\n\n/start - Restart interaction or show main menu\n/profile - View or manage your profile\n/find_match - Find a match\n/help - Show this help message".to_string();
 
-    // Example: Add admin commands to help if user is admin
-    if user.roles.contains(&UserRole::Admin) {
-        help_text.push_str("\n\nAdmin Commands:\n/status - Check bot status");
-        // Add other admin commands
+    let mut help_text = "Available commands:\n\n/start - Restart interaction or show main menu".to_string();
+    for command in command_registry() {
+        if command.required_role.as_ref().map_or(true, |role| user.roles.contains(role)) {
+            help_text.push_str(&format!("\n{} - {}", command.name, command.description));
+        }
     }
 
     Response::from_json(&json!({
@@ -78,23 +210,139 @@ async fn handle_help_command(_user_service: &UserService, rbac_service: &RBACSer
     }))
 }
 
-async fn handle_admin_status_command(_user_service: &UserService, _rbac_service: &RBACService, _env_config: &EnvironmentConfig, user: &DomainUser, chat_id: i64, _args: Vec<&str>) -> Result<Response> {
+async fn handle_admin_status_command(env: &Env, user_service: &UserService, _rbac_service: &RBACService, _env_config: &EnvironmentConfig, user: &DomainUser, chat_id: i64, _args: Vec<&str>) -> Result<Response> {
     console_log!("[CmdHandler] /status (admin) for user {}", user.id);
+    let report = MonitoringService::new().health_check(env, user_service).await;
+
     Response::from_json(&json!({
         "method": "sendMessage", "chat_id": chat_id,
-        "text": "Bot status: Healthy! (Admin View)"
+        "text": format_health_report(&report)
     }))
 }
+
+/// Renders a `HealthReport` as the admin-facing `/status` text. `main`'s
+/// `/health` path serves the same report as JSON instead, for external
+/// uptime monitors.
+fn format_health_report(report: &HealthReport) -> String {
+    let mut text = format!("Bot status: {}\nUptime: {}s\n", report.status, report.uptime_seconds);
+    for check in &report.checks {
+        text.push_str(&format!("\n{} {} ({}ms)", if check.ok { "✅" } else { "❌" }, check.name, check.latency_ms));
+    }
+    text
+}
 // --- End Placeholder Command Handlers ---
 
+// --- Command Registry ---
+/// Boxed future returned by a command handler shim, so handler fn pointers
+/// can be stored in a plain `Vec` despite the handlers themselves being
+/// `async fn`s (which can't be named as a `fn` type directly).
+type CommandHandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<Response>> + 'a>>;
+type CommandHandlerFn = for<'a> fn(&'a Env, &'a UserService, &'a RBACService, &'a EnvironmentConfig, &'a DomainUser, i64, Vec<&'a str>) -> CommandHandlerFuture<'a>;
+
+/// One entry in the command table: its Telegram command string, the
+/// `/help` description, the role required to see and run it (`None` means
+/// every user), and the handler to dispatch to. This is the single source
+/// of truth `dispatch_command`, `handle_help_command`, and `setMyCommands`
+/// all read from, so adding a command is one entry here instead of edits
+/// scattered across the three.
+struct CommandSpec {
+    name: &'static str,
+    description: &'static str,
+    required_role: Option<UserRole>,
+    handler: CommandHandlerFn,
+}
+
+fn profile_handler<'a>(env: &'a Env, us: &'a UserService, rs: &'a RBACService, ec: &'a EnvironmentConfig, u: &'a DomainUser, chat_id: i64, args: Vec<&'a str>) -> CommandHandlerFuture<'a> {
+    let _ = env;
+    Box::pin(handle_profile_command(us, rs, ec, u, chat_id, args))
+}
+
+fn find_match_handler<'a>(env: &'a Env, us: &'a UserService, rs: &'a RBACService, ec: &'a EnvironmentConfig, u: &'a DomainUser, chat_id: i64, args: Vec<&'a str>) -> CommandHandlerFuture<'a> {
+    Box::pin(handle_find_match_command(env, us, rs, ec, u, chat_id, args))
+}
+
+fn help_handler<'a>(env: &'a Env, us: &'a UserService, rs: &'a RBACService, ec: &'a EnvironmentConfig, u: &'a DomainUser, chat_id: i64, args: Vec<&'a str>) -> CommandHandlerFuture<'a> {
+    let _ = env;
+    Box::pin(handle_help_command(us, rs, ec, u, chat_id, args))
+}
+
+fn admin_status_handler<'a>(env: &'a Env, us: &'a UserService, rs: &'a RBACService, ec: &'a EnvironmentConfig, u: &'a DomainUser, chat_id: i64, args: Vec<&'a str>) -> CommandHandlerFuture<'a> {
+    Box::pin(handle_admin_status_command(env, us, rs, ec, u, chat_id, args))
+}
+
+/// The declarative command table. Excludes `/start`, which is special-cased
+/// in `main` because it's the one command allowed before a `User` row exists.
+fn command_registry() -> Vec<CommandSpec> {
+    vec![
+        CommandSpec { name: "/profile", description: "View or manage your profile", required_role: None, handler: profile_handler },
+        CommandSpec { name: "/find_match", description: "Find a match", required_role: None, handler: find_match_handler },
+        CommandSpec { name: "/help", description: "Show this help message", required_role: None, handler: help_handler },
+        CommandSpec { name: "/status", description: "Check bot status", required_role: Some(UserRole::Admin), handler: admin_status_handler },
+    ]
+}
+
+/// Registers the visible (non-role-gated) subset of `command_registry()` as
+/// Telegram's command menu via `setMyCommands`. Only needs to run once per
+/// isolate, guarded by `COMMANDS_REGISTERED`.
+static COMMANDS_REGISTERED: OnceLock<()> = OnceLock::new();
+
+async fn register_commands_once(env: &Env) {
+    if COMMANDS_REGISTERED.get().is_some() {
+        return;
+    }
+
+    let token = match env.secret("TELEGRAM_BOT_TOKEN") {
+        Ok(t) => t.to_string(),
+        Err(e) => {
+            console_warn!("[Main] TELEGRAM_BOT_TOKEN not configured ({}); skipping setMyCommands.", e);
+            return;
+        }
+    };
+
+    let commands: Vec<_> = command_registry().into_iter()
+        .filter(|c| c.required_role.is_none())
+        .map(|c| json!({"command": c.name.trim_start_matches('/'), "description": c.description}))
+        .collect();
+
+    let url = format!("https://api.telegram.org/bot{}/setMyCommands", token);
+    let body = json!({"commands": commands}).to_string();
+
+    let mut headers = Headers::new();
+    if let Err(e) = headers.set("Content-Type", "application/json") {
+        console_error!("[Main] Failed to build setMyCommands request headers: {}", e);
+        return;
+    }
+    let mut init = RequestInit::new();
+    init.method = Method::Post;
+    init.headers = headers;
+    init.body = Some(body.into());
+
+    match Request::new_with_init(&url, &init) {
+        Ok(request) => {
+            if let Err(e) = Fetch::Request(request).send().await {
+                console_error!("[Main] setMyCommands call failed: {}", e);
+            } else {
+                console_log!("[Main] setMyCommands registered {} command(s).", commands.len());
+            }
+        }
+        Err(e) => console_error!("[Main] Failed to build setMyCommands request: {}", e),
+    }
+
+    let _ = COMMANDS_REGISTERED.set(());
+}
+// --- End Command Registry ---
+
 
 // --- Command Dispatcher ---
 async fn dispatch_command(
+    env: &Env,
     user_service: &UserService,
     rbac_service: &RBACService,
     env_config: &EnvironmentConfig,
     telegram_user_opt: Option<TelegramUser>,
+    reply_to_telegram_user: Option<TelegramUser>,
     chat_id: i64,
+    chat_type: &str,
     text: &str, // Full message text
 ) -> Result<Response> {
     let mut parts = text.split_whitespace();
@@ -114,64 +362,453 @@ async fn dispatch_command(
     // For any command other than /start (which handles its own creation), user must exist.
     // /start is handled before this dispatcher is called.
     let domain_user = match user_service.get_user_by_telegram_id(telegram_user.id).await? {
-        Some(user) => {
-            // ---- SESSION TIMEOUT CHECK (Placeholder for dispatched commands) ----
-            let time_since_last_interaction = Utc::now().signed_duration_since(user.last_interaction_at);
-            if time_since_last_interaction > Duration::minutes(env_config.session_timeout_minutes.into())
-                && user.state != UserState::Onboarding
-            {
-                console_log!("[Dispatcher] User {} session timed out for command '{}'. Last seen {} mins ago.",
-                    user.id, command_str, time_since_last_interaction.num_minutes());
-                // TODO: Define behavior for timed-out sessions (e.g., force /start, clear state, etc.)
-                // For now, just log. Some commands might be allowed, others might require "re-authentication" via /start.
-                // Potentially return a message asking them to use /start to refresh their session.
-                // return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Your session has expired. Please use /start to continue."}));
-            }
-            // ---- END SESSION TIMEOUT CHECK ----
-            user
-        },
+        Some(user) => user,
         None => {
             console_warn!("[Dispatcher] User {} not found for command '{}'. Must /start first.", telegram_user.id, command_str);
             return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Please use /start to begin."}));
         }
     };
 
-    // RBAC Check
-    if !rbac_service.check_permission(&domain_user.roles, &command_str) {
-        console_warn!("[Dispatcher] User {} (roles: {:?}) DENIED for command '{}'", domain_user.id, domain_user.roles, command_str);
-        return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "You don't have permission for that."}));
+    let moderation_service = ModerationService::new(env)?;
+
+    // Session timeout, ban enforcement, mute enforcement, RBAC, and
+    // interaction recording are factored out into a HookRegistry instead of
+    // being duplicated in every handler; see command_hooks for the built-in
+    // hooks and the per-command override mechanism. `MuteStateHook` both
+    // lazily lifts an expired mute and short-circuits while one is still
+    // active, since Workers have no background timer to lift it on its own.
+    let ctx = CommandContext {
+        env, user_service, rbac_service, moderation_service: &moderation_service, env_config, domain_user: &domain_user, chat_id, command_str: &command_str,
+    };
+    let hooks = HookRegistry::new(HookChain::new(vec![
+        Box::new(SessionTimeoutHook), Box::new(BanStateHook), Box::new(MuteStateHook), Box::new(RbacHook), Box::new(InteractionRecorderHook),
+    ]));
+
+    hooks.run(&ctx, || async {
+        // /ban, /unban, /mute, and /unmute need the replied-to target and
+        // chat context a registry handler doesn't carry, so they're
+        // special-cased here ahead of the registry lookup, the same way
+        // /start is special-cased in main.
+        if matches!(command_str.as_str(), "/ban" | "/unban" | "/mute" | "/unmute") {
+            return handle_moderation_command(
+                env, user_service, &moderation_service, &domain_user, reply_to_telegram_user, chat_id, chat_type, &command_str, args,
+            ).await;
+        }
+
+        // /grant_role and /revoke_role need the replied-to target the same
+        // way /ban et al. do, so they're special-cased here too.
+        if matches!(command_str.as_str(), "/grant_role" | "/revoke_role") {
+            return handle_role_command(
+                user_service, rbac_service, &domain_user, reply_to_telegram_user, chat_id, &command_str, args,
+            ).await;
+        }
+
+        // Dispatch to specific command handlers via the declarative registry
+        match command_registry().into_iter().find(|c| c.name == command_str) {
+            Some(spec) => {
+                if spec.required_role.as_ref().map_or(false, |role| !domain_user.roles.contains(role)) {
+                    return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "This command requires a higher role."}));
+                }
+                (spec.handler)(env, user_service, rbac_service, env_config, &domain_user, chat_id, args).await
+            }
+            None => {
+                console_log!("[Dispatcher] Unknown command: {}", command_str);
+                Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": format!("Unknown command: {}. Try /help.", command_str)}))
+            }
+        }
+    }).await
+}
+// --- End Command Dispatcher ---
+
+/// Resolves the target of `/ban`, `/mute`, or `/unmute` — either the
+/// replied-to message's sender, or an explicit first argument holding a
+/// Telegram id or internal user id — to an internal user id.
+async fn resolve_moderation_target(
+    user_service: &UserService,
+    reply_to_telegram_user: Option<TelegramUser>,
+    args: &[&str],
+) -> Result<Option<String>> {
+    if let Some(arg) = args.first() {
+        if let Ok(telegram_id) = arg.parse::<i64>() {
+            if let Some(user) = user_service.get_user_by_telegram_id(telegram_id).await? {
+                return Ok(Some(user.id));
+            }
+        }
+        if let Some(user) = user_service.get_user_by_id(arg).await? {
+            return Ok(Some(user.id));
+        }
     }
-    console_log!("[Dispatcher] User {} (roles: {:?}) ALLOWED for command '{}'", domain_user.id, domain_user.roles, command_str);
-
-    // Dispatch to specific command handlers
-    match command_str.as_str() {
-        "/profile" => handle_profile_command(user_service, rbac_service, env_config, &domain_user, chat_id, args).await,
-        "/find_match" => handle_find_match_command(user_service, rbac_service, env_config, &domain_user, chat_id, args).await,
-        "/help" => handle_help_command(user_service, rbac_service, env_config, &domain_user, chat_id, args).await,
-        "/status" => { // Example: Admin command check can also be here if more granular than RBACService for some reason
-            if domain_user.roles.contains(&UserRole::Admin) { // Double check, though RBACService should handle it.
-                handle_admin_status_command(user_service, rbac_service, env_config, &domain_user, chat_id, args).await
-            } else {
-                 Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "This command is admin-only."}))
+    if let Some(tu) = reply_to_telegram_user {
+        if let Some(user) = user_service.get_user_by_telegram_id(tu.id).await? {
+            return Ok(Some(user.id));
+        }
+    }
+    Ok(None)
+}
+
+/// Fires `banChatMember` or `restrictChatMember` out-of-band so the
+/// restriction also applies at the Telegram group level, not just in our own
+/// `bans`/`mutes` bookkeeping. No-ops with a warning if `TELEGRAM_BOT_TOKEN`
+/// isn't configured, matching `answer_callback_query`.
+async fn apply_group_restriction(env: &Env, method: &str, chat_id: i64, telegram_user_id: i64, until_date: Option<DateTime<Utc>>) -> Result<()> {
+    let token = match env.secret("TELEGRAM_BOT_TOKEN") {
+        Ok(t) => t.to_string(),
+        Err(e) => {
+            console_warn!("[Moderation] TELEGRAM_BOT_TOKEN not configured ({}); skipping {}.", e, method);
+            return Ok(());
+        }
+    };
+
+    let url = format!("https://api.telegram.org/bot{}/{}", token, method);
+    let mut payload = json!({"chat_id": chat_id, "user_id": telegram_user_id});
+    if method == "restrictChatMember" {
+        payload["permissions"] = json!({"can_send_messages": false});
+    }
+    if let Some(until) = until_date {
+        payload["until_date"] = json!(until.timestamp());
+    }
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    let mut init = RequestInit::new();
+    init.method = Method::Post;
+    init.headers = headers;
+    init.body = Some(payload.to_string().into());
+
+    let request = Request::new_with_init(&url, &init)?;
+    Fetch::Request(request).send().await?;
+    Ok(())
+}
+
+/// Pulls an optional `delay:<duration>` token (e.g. `delay:10m`) out of
+/// `args`, returning the remaining args in order plus the delay string if
+/// one was present. Lets `/ban`/`/mute` schedule a restriction to start
+/// later instead of immediately.
+fn extract_delay_arg<'a>(args: &[&'a str]) -> (Vec<&'a str>, Option<&'a str>) {
+    let mut remaining = Vec::with_capacity(args.len());
+    let mut delay = None;
+    for arg in args {
+        match arg.strip_prefix("delay:") {
+            Some(d) => delay = Some(d),
+            None => remaining.push(*arg),
+        }
+    }
+    (remaining, delay)
+}
+
+/// Handles `/ban`, `/unban`, `/mute`, and `/unmute`, special-cased out of the
+/// declarative command registry in `dispatch_command` because they need a
+/// `ModerationService`, the replied-to target, and the chat context.
+/// Restricted to `Role::Admin` regardless of `command_restrictions`, the same
+/// way `handle_role_command` is, since moderation actions are similarly
+/// sensitive; targeting another `Role::Admin` is always rejected.
+async fn handle_moderation_command(
+    env: &Env,
+    user_service: &UserService,
+    moderation_service: &ModerationService,
+    domain_user: &DomainUser,
+    reply_to_telegram_user: Option<TelegramUser>,
+    chat_id: i64,
+    chat_type: &str,
+    command_str: &str,
+    args: Vec<&str>,
+) -> Result<Response> {
+    if !domain_user.roles.contains(&UserRole::Admin) {
+        return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Only admins can moderate users."}));
+    }
+
+    let is_group = chat_type == "group" || chat_type == "supergroup";
+
+    let target_args: Vec<&str> = if reply_to_telegram_user.is_some() { args.clone() } else { args.iter().skip(1).cloned().collect() };
+    let target_user_id = match resolve_moderation_target(user_service, reply_to_telegram_user.clone(), &args).await? {
+        Some(id) => id,
+        None => {
+            return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Reply to the user you want to moderate, or pass their id."}));
+        }
+    };
+
+    if matches!(command_str, "/ban" | "/mute") {
+        let target_roles = user_service.get_user_by_id(&target_user_id).await?.map(|u| u.roles).unwrap_or_default();
+        if target_roles.contains(&UserRole::Admin) {
+            return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Admins can't be banned or muted through this command."}));
+        }
+    }
+
+    let (target_args, delay_str) = extract_delay_arg(&target_args);
+
+    match command_str {
+        "/ban" => {
+            let duration_str = target_args.first().copied();
+            let reason = if target_args.len() > 1 { target_args[1..].join(" ") } else { "No reason given".to_string() };
+            let ban = match moderation_service.ban(&target_user_id, &reason, &domain_user.id, duration_str, delay_str).await {
+                Ok(b) => b,
+                Err(e) => return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": format!("Could not ban: {}", e)})),
+            };
+            if is_group && ban.starts_at <= Utc::now() {
+                if let Some(tu) = reply_to_telegram_user {
+                    apply_group_restriction(env, "banChatMember", chat_id, tu.id, ban.expires_at).await?;
+                }
+            }
+            let when = if ban.starts_at > Utc::now() { format!(" starting {}", ban.starts_at) } else { String::new() };
+            Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": format!("User banned{}{}.", when, ban.expires_at.map(|e| format!(" until {}", e)).unwrap_or_default())}))
+        }
+        "/unban" => {
+            moderation_service.unban(&target_user_id).await?;
+            Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "User unbanned."}))
+        }
+        "/mute" => {
+            let duration_str = match target_args.first() {
+                Some(d) => *d,
+                None => return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Usage: /mute <duration e.g. 10m/2h/1d> [delay:<duration>] (reply to the user)"})),
+            };
+            let mute = match moderation_service.mute(&target_user_id, duration_str, &domain_user.id, delay_str).await {
+                Ok(m) => m,
+                Err(e) => return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": format!("Could not mute: {}", e)})),
+            };
+            if is_group && mute.starts_at <= Utc::now() {
+                if let Some(tu) = reply_to_telegram_user {
+                    apply_group_restriction(env, "restrictChatMember", chat_id, tu.id, Some(mute.unrestrict_at)).await?;
+                }
+            }
+            let when = if mute.starts_at > Utc::now() { format!(" starting {}", mute.starts_at) } else { String::new() };
+            Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": format!("User muted{} until {}.", when, mute.unrestrict_at)}))
+        }
+        "/unmute" => {
+            moderation_service.unmute(&target_user_id).await?;
+            Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "User unmuted."}))
+        }
+        _ => unreachable!("handle_moderation_command only called for /ban, /unban, /mute, /unmute"),
+    }
+}
+
+/// Parses a `/grant_role`/`/revoke_role` role argument. Only the global
+/// roles are settable through this command; scoped `GroupModerator`/
+/// `GroupAdmin` grants go through `RBACService::grant_role` directly.
+fn parse_role_arg(s: &str) -> Option<UserRole> {
+    match s.to_lowercase().as_str() {
+        "user" => Some(UserRole::User),
+        "moderator" => Some(UserRole::Moderator),
+        "admin" => Some(UserRole::Admin),
+        _ => None,
+    }
+}
+
+/// Handles `/grant_role` and `/revoke_role`, special-cased out of the
+/// declarative command registry in `dispatch_command` because they need the
+/// replied-to target, the same as `handle_moderation_command`. Restricted to
+/// `Role::Admin` regardless of `command_restrictions`, since handing out
+/// roles is sensitive enough that it shouldn't be reconfigurable at runtime.
+/// `/grant_role` takes an optional trailing duration (e.g. `2h`, `30m`); when
+/// given, the grant is time-limited.
+async fn handle_role_command(
+    user_service: &UserService,
+    rbac_service: &RBACService,
+    actor: &DomainUser,
+    reply_to_telegram_user: Option<TelegramUser>,
+    chat_id: i64,
+    command_str: &str,
+    args: Vec<&str>,
+) -> Result<Response> {
+    // RBAC already gated `command_str` via the hook chain, but that's
+    // reconfigurable at runtime through `command_restrictions`; granting and
+    // revoking roles is sensitive enough to require actual `Role::Admin`
+    // regardless of that config.
+    if !actor.roles.contains(&UserRole::Admin) {
+        return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Only admins can grant or revoke roles."}));
+    }
+
+    let grant = command_str == "/grant_role";
+    let target_args: Vec<&str> = if reply_to_telegram_user.is_some() { args.clone() } else { args.iter().skip(1).cloned().collect() };
+
+    let role = match target_args.first().and_then(|r| parse_role_arg(r)) {
+        Some(role) => role,
+        None => return Response::from_json(&json!({
+            "method": "sendMessage", "chat_id": chat_id,
+            "text": format!("Usage: {} <user_id> <user|moderator|admin> [duration, e.g. 2h] (or reply to the user)", command_str)
+        })),
+    };
+
+    let target_user_id = match resolve_moderation_target(user_service, reply_to_telegram_user, &args).await? {
+        Some(id) => id,
+        None => return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Reply to the user you want to change, or pass their id."})),
+    };
+
+    if !RBACService::can_change_role(&actor.roles, &role) {
+        return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "You can't grant or revoke a role equal to or above your own."}));
+    }
+
+    let target = match user_service.get_user_by_id(&target_user_id).await? {
+        Some(u) => u,
+        None => return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "User not found."})),
+    };
+
+    let expires_at = match (grant, target_args.get(1)) {
+        (true, Some(duration_str)) => match config_service::parse_duration_seconds(duration_str) {
+            Some(secs) => Some(Utc::now() + Duration::seconds(secs)),
+            None => return Response::from_json(&json!({
+                "method": "sendMessage", "chat_id": chat_id,
+                "text": format!("'{}' isn't a valid duration (e.g. '30m', '2h', '1d').", duration_str)
+            })),
+        },
+        _ => None,
+    };
+
+    let result = apply_role_change(user_service, rbac_service, &target, &role, grant, &actor.id, expires_at).await;
+    let text = match result {
+        ChangeResult::Success(msg) | ChangeResult::Failed(msg) | ChangeResult::NoChange(msg) => msg,
+    };
+    Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": text}))
+}
+
+/// Grants or revokes `role` on `target`. A time-limited grant (`expires_at`
+/// given) is persisted via `RBACService::grant_role` into the `user_roles`
+/// table instead of the flat `User.roles` column, so it actually lapses on
+/// its own once `expires_at` passes — `check_permission` merges both sources
+/// via `effective_roles`. A permanent grant or a revoke still goes through
+/// `User.roles` directly, guarding against duplicating an already-held role
+/// (or revoking one never held) and re-reading the user row to confirm the
+/// write landed before reporting success.
+async fn apply_role_change(user_service: &UserService, rbac_service: &RBACService, target: &DomainUser, role: &UserRole, grant: bool, changed_by: &str, expires_at: Option<chrono::DateTime<Utc>>) -> ChangeResult {
+    if let Some(expires_at) = expires_at {
+        return match rbac_service.grant_role(&target.id, role, None, changed_by, Some(expires_at)).await {
+            Ok(()) => ChangeResult::Success(format!("{:?} role granted to {} until {}.", role, target.id, expires_at.to_rfc3339())),
+            Err(e) => {
+                console_error!("[RoleChange] Failed to persist temporary {:?} grant for {}: {}", role, target.id, e);
+                ChangeResult::Failed(format!("Failed to grant a temporary role for {}.", target.id))
             }
+        };
+    }
+
+    let already_has_it = target.roles.contains(role);
+    if grant == already_has_it {
+        return if grant {
+            ChangeResult::NoChange(format!("{} already has the {:?} role.", target.id, role))
+        } else {
+            ChangeResult::NoChange(format!("{} doesn't have the {:?} role.", target.id, role))
+        };
+    }
+
+    let mut new_roles = target.roles.clone();
+    if grant {
+        new_roles.push(role.clone());
+    } else {
+        new_roles.retain(|r| r != role);
+    }
+
+    match user_service.update_user_roles(&target.id, new_roles, Some(changed_by)).await {
+        Ok(updated) if updated.roles.contains(role) == grant => {
+            ChangeResult::Success(format!("{:?} role {} for {}.", role, if grant { "granted" } else { "revoked" }, target.id))
+        }
+        Ok(_) => ChangeResult::Failed(format!("Role change for {} didn't take effect.", target.id)),
+        Err(e) => {
+            console_error!("[RoleChange] Failed to update roles for {}: {}", target.id, e);
+            ChangeResult::Failed(format!("Failed to update roles for {}.", target.id))
+        }
+    }
+}
+
+// --- Callback Query Dispatcher ---
+/// Fires `answerCallbackQuery` out-of-band via a direct Telegram API call,
+/// since a webhook response can only carry one method (used here for
+/// `editMessageText` instead). No-ops with a warning if `TELEGRAM_BOT_TOKEN`
+/// isn't configured, matching how other optional secrets are treated
+/// elsewhere in this codebase.
+async fn answer_callback_query(env: &Env, callback_query_id: &str, text: &str) -> Result<()> {
+    let token = match env.secret("TELEGRAM_BOT_TOKEN") {
+        Ok(t) => t.to_string(),
+        Err(e) => {
+            console_warn!("[Callback] TELEGRAM_BOT_TOKEN not configured ({}); skipping answerCallbackQuery.", e);
+            return Ok(());
+        }
+    };
+
+    let url = format!("https://api.telegram.org/bot{}/answerCallbackQuery", token);
+    let body = json!({"callback_query_id": callback_query_id, "text": text}).to_string();
+
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    let mut init = RequestInit::new();
+    init.method = Method::Post;
+    init.headers = headers;
+    init.body = Some(body.into());
+
+    let request = Request::new_with_init(&url, &init)?;
+    Fetch::Request(request).send().await?;
+    Ok(())
+}
+
+/// Routes an inline-keyboard tap (`update.callback_query`) parallel to
+/// `dispatch_command`: looks up the tapping user, runs RBAC, performs the
+/// `match:accept:<user_id>` / `match:reject:<user_id>` action, and answers
+/// with `answerCallbackQuery` plus an `editMessageText` that updates the
+/// card in place.
+async fn dispatch_callback(
+    env: &Env,
+    user_service: &UserService,
+    rbac_service: &RBACService,
+    callback: CallbackQuery,
+) -> Result<Response> {
+    console_log!("[CallbackDispatcher] data='{}' from telegram user {}", callback.data, callback.from.id);
+
+    let domain_user = match user_service.get_user_by_telegram_id(callback.from.id).await? {
+        Some(user) => user,
+        None => {
+            answer_callback_query(env, &callback.id, "Please /start first.").await?;
+            return Response::empty();
+        }
+    };
+
+    let scope = callback.message.as_ref().map(|m| m.chat.id.to_string());
+    if !rbac_service.check_permission(&domain_user.id, &domain_user.roles, "/find_match", scope.as_deref()).await? {
+        console_warn!("[CallbackDispatcher] User {} DENIED for callback '{}'", domain_user.id, callback.data);
+        answer_callback_query(env, &callback.id, "You don't have permission for that.").await?;
+        return Response::empty();
+    }
+
+    let mut parts = callback.data.splitn(3, ':');
+    let (ack_text, card_text) = match (parts.next(), parts.next(), parts.next()) {
+        (Some("match"), Some("accept"), Some(target_id)) => {
+            console_log!("[CallbackDispatcher] User {} accepted match with {}", domain_user.id, target_id);
+            let matching_service = MatchingService::new(env)?;
+            matching_service.record_decision(&domain_user.id, target_id, true).await?;
+            ("Match request sent!".to_string(), format!("You accepted {}'s profile. ✅", target_id))
+        }
+        (Some("match"), Some("reject"), Some(target_id)) => {
+            console_log!("[CallbackDispatcher] User {} passed on match with {}", domain_user.id, target_id);
+            let matching_service = MatchingService::new(env)?;
+            matching_service.record_decision(&domain_user.id, target_id, false).await?;
+            ("Passed.".to_string(), format!("You passed on {}'s profile.", target_id))
         }
         _ => {
-            console_log!("[Dispatcher] Unknown command: {}", command_str);
-            Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": format!("Unknown command: {}. Try /help.", command_str)}))
+            console_warn!("[CallbackDispatcher] Unrecognized callback data: '{}'", callback.data);
+            ("Unknown action.".to_string(), "This button is no longer valid.".to_string())
         }
+    };
+
+    answer_callback_query(env, &callback.id, &ack_text).await?;
+
+    match callback.message {
+        Some(message) => Response::from_json(&json!({
+            "method": "editMessageText",
+            "chat_id": message.chat.id,
+            "message_id": message.message_id,
+            "text": card_text
+        })),
+        None => Response::empty(),
     }
 }
-// --- End Command Dispatcher ---
+// --- End Callback Query Dispatcher ---
 
 
 // --- Core Command Handlers (modified signatures) ---
 async fn handle_start_command(
+    env: &Env,
     user_service: &UserService,
     rbac_service: &RBACService,
     env_config: &EnvironmentConfig,
     telegram_user_opt: Option<TelegramUser>,
     chat_id: i64
-) -> Result<Response> { // Return just Response, user_id for interaction is handled in main
+) -> Result<Response> { // Return just Response, user_id for interaction is handled by InteractionRecorderHook
     console_log!("[StartHandler] /start for chat_id: {}", chat_id);
 
     let telegram_user = match telegram_user_opt {
@@ -182,71 +819,71 @@ async fn handle_start_command(
         }
     };
 
-    match user_service.get_user_by_telegram_id(telegram_user.id).await {
-        Ok(Some(mut domain_user)) => {
-            console_log!("[StartHandler] Existing user: id={}, roles: {:?}, last_interaction: {}", domain_user.id, domain_user.roles, domain_user.last_interaction_at);
-
-            // Session timeout check (conceptual)
-            let time_since_last_interaction = Utc::now().signed_duration_since(domain_user.last_interaction_at);
-            if time_since_last_interaction > Duration::minutes(env_config.session_timeout_minutes.into()) && domain_user.state != UserState::Onboarding {
-                console_log!("[StartHandler] User {} session timed out ({} mins ago).", domain_user.id, time_since_last_interaction.num_minutes());
-                // Potentially reset state or re-verify. For /start, usually means refresh.
-            }
-
-            if !rbac_service.check_permission(&domain_user.roles, "/start") {
-                console_error!("[StartHandler] User {} DENIED /start. Roles: {:?}", domain_user.id, domain_user.roles);
-                return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Access denied."}));
-            }
-
-            if domain_user.state == UserState::Blocked {
-                return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Your account is blocked."}));
-            }
-
-            if domain_user.is_profile_complete() {
-                let user_name = domain_user.name.as_deref().unwrap_or("there");
-                let menu_text = format!("Welcome back, {}!\n\nMenu:\n/find_match\n/profile\n/help", user_name);
-                return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": menu_text}));
-            } else {
-                if domain_user.name.is_none() {
-                    return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Welcome! What's your name?"}));
-                } else {
-                    match user_service.update_user_state_and_name(domain_user.id.clone(), domain_user.name.clone(), UserState::Active).await {
-                        Ok(updated_user) => {
-                            let menu_text = format!("Thanks, {}! Profile active.\n\nMenu:\n/find_match\n/profile\n/help", updated_user.name.as_deref().unwrap_or_default());
-                            return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": menu_text}));
-                        }
-                        Err(e) => {
-                            console_error!("[StartHandler] Failed to activate user {}: {}", domain_user.id, e);
-                            return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Error activating profile."}));
-                        }
-                    }
-                }
-            }
-        }
-        Ok(None) => { // New user
-            match user_service.create_user_from_telegram_user(&telegram_user).await {
+    let domain_user = match user_service.get_user_by_telegram_id(telegram_user.id).await {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return match user_service.create_user_from_telegram_user(&telegram_user, rbac_service).await {
                 Ok(new_user) => {
                     console_log!("[StartHandler] New user created: id={}, roles: {:?}", new_user.id, new_user.roles);
-                    if !rbac_service.check_permission(&new_user.roles, "/start") {
+                    if !rbac_service.check_permission(&new_user.id, &new_user.roles, "/start", Some(&chat_id.to_string())).await? {
                         console_error!("[StartHandler] New user {} DENIED /start. Roles: {:?}", new_user.id, new_user.roles);
                         return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Account permission error."}));
                     }
-                    return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Welcome! What's your name?"}));
+                    Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Welcome! What's your name?"}))
                 }
                 Err(e) => {
                     console_error!("[StartHandler] Failed to create user {}: {}", telegram_user.id, e);
-                    return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Account creation failed."}));
+                    Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Account creation failed."}))
                 }
-            }
+            };
         }
         Err(e) => {
             console_error!("[StartHandler] DB error for {}: {}", telegram_user.id, e);
             return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Error fetching account."}));
         }
-    }
+    };
+
+    console_log!("[StartHandler] Existing user: id={}, roles: {:?}, last_interaction: {}", domain_user.id, domain_user.roles, domain_user.last_interaction_at);
+
+    let moderation_service = ModerationService::new(env)?;
+
+    // Session timeout, ban enforcement, RBAC, and interaction recording are
+    // factored out into a HookRegistry instead of being duplicated in every
+    // handler; see command_hooks for the built-in hooks and the per-command
+    // override mechanism.
+    let ctx = CommandContext {
+        env, user_service, rbac_service, moderation_service: &moderation_service, env_config, domain_user: &domain_user, chat_id, command_str: "/start",
+    };
+    let hooks = HookRegistry::new(HookChain::new(vec![
+        Box::new(SessionTimeoutHook), Box::new(BanStateHook), Box::new(RbacHook), Box::new(InteractionRecorderHook),
+    ]));
+
+    hooks.run(&ctx, || async {
+        if domain_user.is_profile_complete() {
+            let user_name = domain_user.name.as_deref().unwrap_or("there");
+            let menu_text = format!("Welcome back, {}!\n\nMenu:\n/find_match\n/profile\n/help", user_name);
+            return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": menu_text}));
+        }
+
+        if domain_user.name.is_none() {
+            return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Welcome! What's your name?"}));
+        }
+
+        match user_service.update_user_state_and_name(domain_user.id.clone(), domain_user.name.clone(), UserState::Active).await {
+            Ok(updated_user) => {
+                let menu_text = format!("Thanks, {}! Profile active.\n\nMenu:\n/find_match\n/profile\n/help", updated_user.name.as_deref().unwrap_or_default());
+                Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": menu_text}))
+            }
+            Err(e) => {
+                console_error!("[StartHandler] Failed to activate user {}: {}", domain_user.id, e);
+                Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Error activating profile."}))
+            }
+        }
+    }).await
 }
 
 async fn handle_onboarding_message(
+    env: &Env,
     user_service: &UserService,
     _env_config: &EnvironmentConfig, // Keep for future use if needed
     telegram_user_opt: Option<TelegramUser>,
@@ -260,6 +897,21 @@ async fn handle_onboarding_message(
 
     match user_service.get_user_by_telegram_id(telegram_user.id).await? {
         Some(current_user) => {
+            // Workers have no background timer, so a mute can only be lifted
+            // by a check like this one running at the top of message handling.
+            // Onboarding text isn't a slash command, so it never passes through
+            // MuteStateHook/the HookChain; enforce it here instead.
+            let moderation_service = ModerationService::new(env)?;
+            match moderation_service.check_and_lift_mute(&current_user.id).await {
+                Ok(Some(mute)) => {
+                    return Response::from_json(&json!({
+                        "method": "sendMessage", "chat_id": chat_id,
+                        "text": format!("You're muted until {}.", mute.unrestrict_at)
+                    }));
+                }
+                Ok(None) => {}
+                Err(e) => console_error!("[OnboardingHandler] Failed to check/lift mute for user {}: {}", current_user.id, e),
+            }
             if current_user.state == UserState::Onboarding && current_user.name.is_none() {
                 console_log!("[OnboardingHandler] User {} processing name: '{}'", current_user.id, text);
                 let name_to_set = text.trim();
@@ -289,6 +941,35 @@ async fn handle_onboarding_message(
 #[event(fetch)]
 pub async fn main(mut _req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
     utils::set_panic_hook();
+
+    // Lets external uptime monitors poll the worker directly instead of
+    // sending fake Telegram updates through the webhook path below.
+    if _req.path() == "/health" {
+        let user_service = match UserService::new(&env) {
+            Ok(s) => s,
+            Err(e) => {
+                console_error!("[Main] Failed to init UserService for /health: {}", e);
+                return Response::error("Internal error", 500);
+            }
+        };
+        let report = MonitoringService::new().health_check(&env, &user_service).await;
+        let status_code = if report.is_healthy() { 200 } else { 503 };
+        return Response::from_json(&report)?.with_status(status_code);
+    }
+
+    // Serves uploaded media straight from R2 instead of requiring a public
+    // bucket domain, so `get_media_public_url`'s `/media/<key>` paths resolve.
+    if let Some(object_key) = _req.path().strip_prefix("/media/") {
+        let media_service = match media_service::MediaService::new(&env) {
+            Ok(s) => s,
+            Err(e) => {
+                console_error!("[Main] Failed to init MediaService for /media: {}", e);
+                return Response::error("Internal error", 500);
+            }
+        };
+        return media_service.serve_media(object_key, &_req.headers()).await;
+    }
+
     let method = _req.method();
 
     if method != Method::Post {
@@ -296,13 +977,13 @@ pub async fn main(mut _req: Request, env: Env, _ctx: worker::Context) -> Result<
     }
 
     let config_service = ConfigService::new();
-    let env_config = match config_service.get_environment_config(&env).await {
-        Ok(config) => config,
-        Err(e) => {
-            console_error!("[Main] Critical error loading env config: {}. Using defaults.", e);
-            EnvironmentConfig::default()
-        }
-    };
+    // `load` resolves EnvironmentConfig the same way `get_environment_config`
+    // always has, plus FeatureFlags layered default/KV/env-override; the
+    // flags aren't consumed by a handler yet, but are loaded with their full
+    // precedence so a future one can read them without more plumbing here.
+    let AppConfig { environment: env_config, feature_flags: _feature_flags } = config_service.load(&env).await;
+
+    register_commands_once(&env).await;
 
     let update: TelegramUpdate = match _req.json().await {
         Ok(upd) => {
@@ -320,7 +1001,9 @@ pub async fn main(mut _req: Request, env: Env, _ctx: worker::Context) -> Result<
 
     if let Some(message) = update.message {
         let chat_id = message.chat.id;
+        let chat_type = message.chat.chat_type.clone();
         let telegram_user_opt = message.from.clone();
+        let reply_to_telegram_user = message.reply_to_message.as_ref().and_then(|m| m.from.clone());
 
         let user_service = match UserService::new(&env) {
             Ok(s) => s,
@@ -329,27 +1012,27 @@ pub async fn main(mut _req: Request, env: Env, _ctx: worker::Context) -> Result<
                 return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Internal error. Try later."}));
             }
         };
-        let rbac_service = RBACService::new();
+        let rbac_service = match RBACService::new(&env) {
+            Ok(s) => s,
+            Err(e) => {
+                console_error!("[Main] Failed to init RBACService: {}", e);
+                return Response::from_json(&json!({"method": "sendMessage", "chat_id": chat_id, "text": "Internal error. Try later."}));
+            }
+        };
 
         if let Some(text) = message.text {
             let text_trimmed = text.trim();
             if text_trimmed.starts_with("/start") {
-                // handle_start_command returns only Response now
-                final_response = handle_start_command(&user_service, &rbac_service, &env_config, telegram_user_opt.clone(), chat_id).await;
-                if let Some(ref tu) = telegram_user_opt {
-                    if let Ok(Some(user)) = user_service.get_user_by_telegram_id(tu.id).await { // Re-fetch to get ID for new/existing user
-                        user_internal_id_for_interaction_update = Some(user.id.clone());
-                    }
-                }
+                // Interaction recording for /start is handled by InteractionRecorderHook.
+                final_response = handle_start_command(&env, &user_service, &rbac_service, &env_config, telegram_user_opt.clone(), chat_id).await;
             } else if text_trimmed.starts_with("/") {
-                final_response = dispatch_command(&user_service, &rbac_service, &env_config, telegram_user_opt.clone(), chat_id, &text_trimmed).await;
-                if let Some(ref tu) = telegram_user_opt {
-                    if let Ok(Some(user)) = user_service.get_user_by_telegram_id(tu.id).await {
-                        user_internal_id_for_interaction_update = Some(user.id.clone());
-                    }
-                }
+                // Interaction recording for dispatched commands is handled by InteractionRecorderHook.
+                final_response = dispatch_command(
+                    &env, &user_service, &rbac_service, &env_config, telegram_user_opt.clone(),
+                    reply_to_telegram_user.clone(), chat_id, &chat_type, &text_trimmed,
+                ).await;
             } else {
-                final_response = handle_onboarding_message(&user_service, &env_config, telegram_user_opt.clone(), chat_id, &text_trimmed).await;
+                final_response = handle_onboarding_message(&env, &user_service, &env_config, telegram_user_opt.clone(), chat_id, &text_trimmed).await;
                 if let Some(ref tu) = telegram_user_opt {
                     if let Ok(Some(user)) = user_service.get_user_by_telegram_id(tu.id).await {
                         user_internal_id_for_interaction_update = Some(user.id.clone());
@@ -366,10 +1049,30 @@ pub async fn main(mut _req: Request, env: Env, _ctx: worker::Context) -> Result<
             }
         }
 
+    } else if let Some(callback_query) = update.callback_query {
+        let user_service = match UserService::new(&env) {
+            Ok(s) => s,
+            Err(e) => {
+                console_error!("[Main] Failed to init UserService: {}", e);
+                return Response::empty();
+            }
+        };
+        let rbac_service = match RBACService::new(&env) {
+            Ok(s) => s,
+            Err(e) => {
+                console_error!("[Main] Failed to init RBACService: {}", e);
+                return Response::empty();
+            }
+        };
+        final_response = dispatch_callback(&env, &user_service, &rbac_service, callback_query).await;
     } else {
-        console_log!("[Main] Received update without a message. Ignoring.");
+        console_log!("[Main] Received update without a message or callback_query. Ignoring.");
         // final_response remains default empty 200 OK
     }
 
     final_response
 }
+
+#[cfg(test)]
+#[path = "lib_tests.rs"]
+mod lib_tests;
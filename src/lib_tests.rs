@@ -1,10 +1,10 @@
 // src/lib_tests.rs
-// This file is separate for clarity, but its contents will be put into lib.rs under #[cfg(test)]
-// For the purpose of this tool, imagine this content is appended to src/lib.rs
+// Kept out-of-line from lib.rs for size; included via `#[path] mod lib_tests;` under #[cfg(test)].
 
 use super::*; // Access items in lib.rs (escape_markdown_v2, format_user_profile_view)
-use crate::user_service::{User as DomainUser, UserState}; // Adjust path if your User struct is elsewhere or aliased differently in lib.rs
-use crate::rbac_service::Role; // Assuming Role is used in DomainUser
+use crate::user_service::{User as DomainUser, UserState};
+use crate::rbac_service::Role;
+use crate::formatter::MarkdownV2Formatter;
 use chrono::{TimeZone, Utc};
 
 // Helper to create DomainUser for lib tests
@@ -20,6 +20,7 @@ fn create_domain_user_for_lib_test(
         telegram_id: 123456,
         telegram_username: Some("libtester".to_string()),
         name, age, gender, bio, location_text, latitude, longitude, media_keys,
+        interests: vec![],
         created_at: Utc.datetime_from_str(created_at_str, "%Y-%m-%dT%H:%M:%SZ").unwrap(),
         updated_at: Utc.datetime_from_str(last_interaction_at_str, "%Y-%m-%dT%H:%M:%SZ").unwrap(), // Assuming updated_at is same as last_interaction for simplicity here
         last_interaction_at: Utc.datetime_from_str(last_interaction_at_str, "%Y-%m-%dT%H:%M:%SZ").unwrap(),
@@ -54,7 +55,7 @@ fn test_format_user_profile_view_all_fields_with_markdown_chars() {
         "2023-01-01T10:00:00Z",
         "2023-01-10T12:00:00Z"
     );
-    let formatted_string = format_user_profile_view(&user);
+    let formatted_string = format_user_profile_view(&user, &MarkdownV2Formatter);
 
     // Check for escaped content
     assert!(formatted_string.contains("Name*: Jöhn\\_Doé\\*"), "Name not escaped correctly: {}", formatted_string);
@@ -89,7 +90,7 @@ fn test_format_user_profile_view_some_fields_none() {
         "2022-05-05T15:30:00Z",
         "2022-05-06T18:00:00Z"
     );
-    let formatted_string = format_user_profile_view(&user);
+    let formatted_string = format_user_profile_view(&user, &MarkdownV2Formatter);
 
     assert!(formatted_string.contains("Name*: Jane Doe"), "Name incorrect: {}", formatted_string);
     assert!(formatted_string.contains("Age*: Not set"), "Age should be 'Not set': {}", formatted_string);
@@ -103,12 +104,12 @@ fn test_format_user_profile_view_some_fields_none() {
 #[test]
 fn test_format_user_profile_location_variants_formatting() {
     let user_text_only = create_domain_user_for_lib_test(None,None,None,None, Some("Home Town (Test)".to_string()), None,None,vec![], UserState::Active, vec![], "2023-01-01T00:00:00Z", "2023-01-01T00:00:00Z");
-    let formatted_text_only = format_user_profile_view(&user_text_only);
+    let formatted_text_only = format_user_profile_view(&user_text_only, &MarkdownV2Formatter);
     assert!(formatted_text_only.contains("Location*: Home Town \\(Test\\)"), "Location text only not formatted/escaped correctly: {}", formatted_text_only);
 
     let user_coords_only = create_domain_user_for_lib_test(None,None,None,None, None, Some(1.23456), Some(5.67891),vec![], UserState::Active, vec![], "2023-01-01T00:00:00Z", "2023-01-01T00:00:00Z");
-    let formatted_coords_only = format_user_profile_view(&user_coords_only);
-    assert!(formatted_coords_only.contains("Location*: Lat: 1\\.235, Lon: 5\\.679"), "Location coords only not formatted/escaped correctly: {}", formatted_coords_only); // Note: periods in numbers are not escaped by current func
+    let formatted_coords_only = format_user_profile_view(&user_coords_only, &MarkdownV2Formatter);
+    assert!(formatted_coords_only.contains("Location*: Lat: 1\\.235, Lon: 5\\.679"), "Location coords only not formatted/escaped correctly: {}", formatted_coords_only); // Coordinate periods are escaped like any other dynamic value
 }
 
 #[test]
@@ -127,7 +128,7 @@ fn test_format_user_profile_empty_bio_and_name() {
         "2022-05-05T15:30:00Z",
         "2022-05-06T18:00:00Z"
     );
-    let formatted_string = format_user_profile_view(&user);
+    let formatted_string = format_user_profile_view(&user, &MarkdownV2Formatter);
     assert!(formatted_string.contains("Name*: Not set"));
     assert!(formatted_string.contains("Bio*: Not set"));
 }
@@ -0,0 +1,301 @@
+// src/matching_service/mod.rs
+use std::collections::{HashMap, VecDeque};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use worker::{D1Database, Env, Error, Result, console_log};
+
+use crate::config_service::EnvironmentConfig;
+use crate::user_service::{User, UserState, haversine_distance_km};
+
+/// Radius (km) past which proximity contributes nothing to `compatibility_score`.
+const PROXIMITY_RADIUS_KM: f64 = 50.0;
+
+/// One row of the `matches` table: a pairing produced by a matching run,
+/// tracked through acceptance/rejection via `dispatch_callback`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Match {
+    pub id: String,
+    pub user_a_id: String,
+    pub user_b_id: String,
+    pub score: f64,
+    pub status: String,
+    pub created_at: chrono::DateTime<Utc>,
+    pub updated_at: chrono::DateTime<Utc>,
+}
+
+/// Normalized interest-tag overlap (Jaccard similarity) between two users,
+/// in `[0.0, 1.0]`. `0.0` when either user has no tags.
+fn interest_overlap(a: &User, b: &User) -> f64 {
+    if a.interests.is_empty() || b.interests.is_empty() {
+        return 0.0;
+    }
+    let set_a: std::collections::HashSet<&str> = a.interests.iter().map(String::as_str).collect();
+    let set_b: std::collections::HashSet<&str> = b.interests.iter().map(String::as_str).collect();
+    let intersection = set_a.intersection(&set_b).count();
+    let union = set_a.union(&set_b).count();
+    if union == 0 { 0.0 } else { intersection as f64 / union as f64 }
+}
+
+/// Normalized proximity between two users, in `[0.0, 1.0]` — `1.0` when at
+/// the same point, `0.0` at or beyond `PROXIMITY_RADIUS_KM`. `0.0` if either
+/// user hasn't set a location.
+fn proximity_score(a: &User, b: &User) -> f64 {
+    match (a.latitude, a.longitude, b.latitude, b.longitude) {
+        (Some(lat_a), Some(lng_a), Some(lat_b), Some(lng_b)) => {
+            let distance_km = haversine_distance_km(lat_a, lng_a, lat_b, lng_b);
+            (1.0 - (distance_km / PROXIMITY_RADIUS_KM)).max(0.0)
+        }
+        _ => 0.0,
+    }
+}
+
+/// Weighted compatibility score between two users: interest-tag overlap plus
+/// proximity, each scaled by its configured weight.
+pub fn compatibility_score(a: &User, b: &User, env_config: &EnvironmentConfig) -> f64 {
+    env_config.match_interest_weight * interest_overlap(a, b)
+        + env_config.match_proximity_weight * proximity_score(a, b)
+}
+
+/// Runs Gale–Shapley deferred acceptance over `pool`, with every member
+/// proposing down their own score-ranked preference list, and returns the
+/// resulting tentative pairing as `pool` indices (symmetric: both directions
+/// of a pair are present).
+fn stable_match(pool: &[User], env_config: &EnvironmentConfig) -> HashMap<usize, usize> {
+    let n = pool.len();
+    if n < 2 {
+        return HashMap::new();
+    }
+
+    let scores: Vec<Vec<f64>> = (0..n)
+        .map(|i| (0..n).map(|j| if i == j { f64::MIN } else { compatibility_score(&pool[i], &pool[j], env_config) }).collect())
+        .collect();
+
+    let preferences: Vec<Vec<usize>> = (0..n)
+        .map(|i| {
+            let mut others: Vec<usize> = (0..n).filter(|&j| j != i).collect();
+            others.sort_by(|&a, &b| scores[i][b].partial_cmp(&scores[i][a]).unwrap_or(std::cmp::Ordering::Equal));
+            others
+        })
+        .collect();
+
+    let mut next_proposal: Vec<usize> = vec![0; n];
+    let mut matched: Vec<Option<usize>> = vec![None; n];
+    let mut free: VecDeque<usize> = (0..n).collect();
+
+    while let Some(proposer) = free.pop_front() {
+        if next_proposal[proposer] >= preferences[proposer].len() {
+            continue; // exhausted their list; stays unmatched
+        }
+        let candidate = preferences[proposer][next_proposal[proposer]];
+        next_proposal[proposer] += 1;
+
+        match matched[candidate] {
+            None => {
+                matched[candidate] = Some(proposer);
+                matched[proposer] = Some(candidate);
+            }
+            Some(current_holder) => {
+                if scores[candidate][proposer] > scores[candidate][current_holder] {
+                    matched[current_holder] = None;
+                    free.push_back(current_holder);
+                    matched[candidate] = Some(proposer);
+                    matched[proposer] = Some(candidate);
+                } else {
+                    free.push_back(proposer);
+                }
+            }
+        }
+    }
+
+    matched.into_iter().enumerate().filter_map(|(i, m)| m.map(|j| (i, j))).collect()
+}
+
+/// Produces and persists candidate matches, replacing the `/find_match`
+/// placeholder. Runs a Gale–Shapley deferred-acceptance pass over the pool
+/// of currently-`Active`, searching, unmatched users so neither side is left
+/// with a one-sided pick.
+pub struct MatchingService {
+    db: D1Database,
+}
+
+impl MatchingService {
+    const MATCH_FIELDS: &'static str = "id, user_a_id, user_b_id, score, status, created_at, updated_at";
+
+    pub fn new(env: &Env) -> Result<Self> {
+        let db = env.d1("DB")?;
+        Ok(Self { db })
+    }
+
+    /// Returns the active (`pending` or `accepted`) match row involving
+    /// `user_id`, if any.
+    async fn active_match_for(&self, user_id: &str) -> Result<Option<Match>> {
+        let sql = format!(
+            "SELECT {} FROM matches WHERE (user_a_id = ?1 OR user_b_id = ?1) AND status IN ('pending', 'accepted') \
+             ORDER BY created_at DESC LIMIT 1",
+            Self::MATCH_FIELDS
+        );
+        self.db.prepare(&sql).bind(&[user_id.into()])?.first::<Match>(None).await
+    }
+
+    /// Every user id currently tied up in a `pending`/`accepted` match, used
+    /// to keep already-matched users out of a fresh matching pool.
+    async fn matched_user_ids(&self) -> Result<std::collections::HashSet<String>> {
+        let sql = "SELECT user_a_id, user_b_id FROM matches WHERE status IN ('pending', 'accepted')";
+        let results = self.db.prepare(sql).all().await?;
+        let rows: Vec<std::collections::HashMap<String, String>> = results.results()?;
+        Ok(rows.into_iter().flat_map(|r| [r.get("user_a_id").cloned(), r.get("user_b_id").cloned()]).flatten().collect())
+    }
+
+    async fn insert_match(&self, user_a_id: &str, user_b_id: &str, score: f64) -> Result<Match> {
+        let id = worker::Uuid::new_v4().to_string();
+        let now = Utc::now();
+        console_log!("[MatchingService] Pairing {} with {} (score {:.3})", user_a_id, user_b_id, score);
+        let sql = format!(
+            "INSERT INTO matches (id, user_a_id, user_b_id, score, status, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, 'pending', ?5, ?5) RETURNING {}",
+            Self::MATCH_FIELDS
+        );
+        self.db.prepare(&sql)
+            .bind(&[id.clone().into(), user_a_id.into(), user_b_id.into(), score.into(), now.to_rfc3339().into()])?
+            .first::<Match>(None).await?
+            .ok_or_else(|| Error::RustError(format!("match {} not found after insert", id)))
+    }
+
+    /// Finds (or returns the already-pending) match for `requester`. Guards
+    /// against self-match and against pairing with an already-matched user.
+    pub async fn find_match_for(&self, requester: &User, all_active: Vec<User>, env_config: &EnvironmentConfig) -> Result<Option<(User, f64)>> {
+        if let Some(existing) = self.active_match_for(&requester.id).await? {
+            // The requester already holds a pending/accepted match; return it
+            // (or nothing, if the partner isn't in `all_active`) rather than
+            // falling through to the pool below, which would create a second
+            // match for them.
+            let partner_id = if existing.user_a_id == requester.id { existing.user_b_id } else { existing.user_a_id };
+            return Ok(all_active.iter().find(|u| u.id == partner_id).map(|partner| (partner.clone(), existing.score)));
+        }
+
+        let already_matched = self.matched_user_ids().await?;
+        let mut pool: Vec<User> = all_active.into_iter()
+            .filter(|u| u.state == UserState::Active && u.latitude.is_some() && u.longitude.is_some())
+            .filter(|u| u.id == requester.id || !already_matched.contains(&u.id))
+            .collect();
+        if !pool.iter().any(|u| u.id == requester.id) {
+            pool.push(requester.clone());
+        }
+        if pool.len() < 2 {
+            return Ok(None);
+        }
+
+        let requester_idx = pool.iter().position(|u| u.id == requester.id).expect("requester pushed into pool above");
+        let pairing = stable_match(&pool, env_config);
+
+        let candidate_idx = match pairing.get(&requester_idx) {
+            Some(&idx) => idx,
+            None => {
+                // Odd pool left the requester unmatched; fall back to their
+                // top-ranked remaining candidate.
+                let mut others: Vec<usize> = (0..pool.len()).filter(|&j| j != requester_idx).collect();
+                others.sort_by(|&a, &b| {
+                    compatibility_score(&pool[b], &pool[requester_idx], env_config)
+                        .partial_cmp(&compatibility_score(&pool[a], &pool[requester_idx], env_config))
+                        .unwrap_or(std::cmp::Ordering::Equal)
+                });
+                match others.into_iter().next() {
+                    Some(idx) => idx,
+                    None => return Ok(None),
+                }
+            }
+        };
+
+        let candidate = pool[candidate_idx].clone();
+        let score = compatibility_score(&pool[requester_idx], &candidate, env_config);
+        self.insert_match(&requester.id, &candidate.id, score).await?;
+        Ok(Some((candidate, score)))
+    }
+
+    /// Records the requester's accept/reject decision on their pending match
+    /// with `candidate_id`.
+    pub async fn record_decision(&self, user_id: &str, candidate_id: &str, accept: bool) -> Result<()> {
+        let status = if accept { "accepted" } else { "rejected" };
+        let now = Utc::now();
+        self.db.prepare(
+            "UPDATE matches SET status = ?1, updated_at = ?2 \
+             WHERE status = 'pending' AND \
+             ((user_a_id = ?3 AND user_b_id = ?4) OR (user_a_id = ?4 AND user_b_id = ?3))"
+        )
+        .bind(&[status.into(), now.to_rfc3339().into(), user_id.into(), candidate_id.into()])?
+        .run().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rbac_service::Role;
+
+    fn make_user(id: &str, lat: Option<f64>, lng: Option<f64>, interests: Vec<&str>) -> User {
+        let now = Utc::now();
+        User {
+            id: id.to_string(), telegram_id: 1, telegram_username: None, name: None, age: None, gender: None, bio: None,
+            location_text: None, latitude: lat, longitude: lng,
+            interests: interests.into_iter().map(String::from).collect(),
+            media_keys: vec![], created_at: now, updated_at: now, last_interaction_at: now,
+            state: UserState::Active, roles: vec![Role::User],
+        }
+    }
+
+    #[test]
+    fn test_interest_overlap_jaccard() {
+        let a = make_user("a", None, None, vec!["hiking", "jazz"]);
+        let b = make_user("b", None, None, vec!["hiking", "chess"]);
+        assert!((interest_overlap(&a, &b) - (1.0 / 3.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_interest_overlap_empty_is_zero() {
+        let a = make_user("a", None, None, vec![]);
+        let b = make_user("b", None, None, vec!["hiking"]);
+        assert_eq!(interest_overlap(&a, &b), 0.0);
+    }
+
+    #[test]
+    fn test_proximity_score_same_point_is_one() {
+        let a = make_user("a", Some(1.0), Some(1.0), vec![]);
+        let b = make_user("b", Some(1.0), Some(1.0), vec![]);
+        assert_eq!(proximity_score(&a, &b), 1.0);
+    }
+
+    #[test]
+    fn test_proximity_score_far_away_is_zero() {
+        let a = make_user("a", Some(0.0), Some(0.0), vec![]);
+        let b = make_user("b", Some(40.0), Some(40.0), vec![]);
+        assert_eq!(proximity_score(&a, &b), 0.0);
+    }
+
+    #[test]
+    fn test_stable_match_pairs_everyone_in_even_pool() {
+        let env_config = EnvironmentConfig::default();
+        let pool = vec![
+            make_user("a", Some(1.0), Some(1.0), vec!["hiking"]),
+            make_user("b", Some(1.0), Some(1.0), vec!["hiking"]),
+            make_user("c", Some(50.0), Some(50.0), vec!["chess"]),
+            make_user("d", Some(50.0), Some(50.0), vec!["chess"]),
+        ];
+        let pairing = stable_match(&pool, &env_config);
+        assert_eq!(pairing.len(), 4);
+        assert_eq!(pairing.get(&0), Some(&1));
+        assert_eq!(pairing.get(&1), Some(&0));
+    }
+
+    #[test]
+    fn test_stable_match_leaves_one_unmatched_in_odd_pool() {
+        let env_config = EnvironmentConfig::default();
+        let pool = vec![
+            make_user("a", Some(1.0), Some(1.0), vec![]),
+            make_user("b", Some(1.0), Some(1.0), vec![]),
+            make_user("c", Some(1.0), Some(1.0), vec![]),
+        ];
+        let pairing = stable_match(&pool, &env_config);
+        assert_eq!(pairing.len(), 2);
+    }
+}
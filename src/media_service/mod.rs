@@ -1,15 +1,28 @@
 // src/media_service/mod.rs
-use worker::{Env, Result, Bucket as R2Bucket, Date, Uuid, R2PutOptions, HttpMetadata};
+use worker::{Env, Result, Bucket as R2Bucket, D1Database, Date, Uuid, R2PutOptions, HttpMetadata, Headers, Response, kv::KvStore};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use aes_gcm::aead::{Aead, KeyInit};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::path::Path;
 use std::collections::HashMap; // For custom metadata example (not used in final put_options yet)
 
+use crate::user_service::MAX_USER_MEDIA_ITEMS;
+
+type HmacSha256 = Hmac<Sha256>;
+
 // Custom error type for MediaService
 #[derive(Debug)]
 pub enum MediaServiceError {
     R2OperationFailed(String),
     InvalidFileName(String),
-    UploadTooLarge, // Example, not yet enforced
+    UploadTooLarge,
     ConfigurationError(String),
+    MediaLimitReached(usize),
+    UnsupportedMediaType(String),
+    EncryptionFailed(String),
 }
 
 impl std::fmt::Display for MediaServiceError {
@@ -19,6 +32,9 @@ impl std::fmt::Display for MediaServiceError {
             MediaServiceError::InvalidFileName(s) => write!(f, "Invalid file name: {}", s),
             MediaServiceError::UploadTooLarge => write!(f, "Uploaded file is too large."),
             MediaServiceError::ConfigurationError(s) => write!(f, "Configuration error: {}", s),
+            MediaServiceError::MediaLimitReached(max) => write!(f, "Media limit ({}) reached.", max),
+            MediaServiceError::UnsupportedMediaType(s) => write!(f, "Unsupported media type: {}", s),
+            MediaServiceError::EncryptionFailed(s) => write!(f, "Media encryption/decryption failed: {}", s),
         }
     }
 }
@@ -33,27 +49,262 @@ impl From<worker::Error> for MediaServiceError {
 }
 
 
+/// A single row of the `user_media` table: one uploaded object owned by one user,
+/// ordered within that user's gallery by `position`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserMedia {
+    pub id: String,
+    pub user_id: String,
+    pub r2_object_key: String,
+    pub position: i64,
+    pub content_type: Option<String>,
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Descriptive metadata a caller can attach to an upload, stored as R2 custom
+/// metadata alongside the object (following Plume's `Media` model).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MediaMeta {
+    pub alt_text: Option<String>,
+    pub sensitive: bool,
+    pub content_warning: Option<String>,
+    /// The caller-supplied file name, kept only so `serve_media` can send it
+    /// back as a `Content-Disposition` suggestion — never used for the R2
+    /// object key itself.
+    pub original_filename: Option<String>,
+}
+
+impl MediaMeta {
+    fn into_custom_metadata(self) -> HashMap<String, String> {
+        let mut map = HashMap::new();
+        if let Some(alt_text) = self.alt_text {
+            map.insert("alt_text".to_string(), alt_text);
+        }
+        map.insert("sensitive".to_string(), self.sensitive.to_string());
+        if let Some(content_warning) = self.content_warning {
+            map.insert("content_warning".to_string(), content_warning);
+        }
+        if let Some(original_filename) = self.original_filename {
+            map.insert("originalFilename".to_string(), original_filename);
+        }
+        map
+    }
+
+    fn from_custom_metadata(map: &HashMap<String, String>) -> Self {
+        Self {
+            alt_text: map.get("alt_text").cloned(),
+            sensitive: map.get("sensitive").map(|s| s == "true").unwrap_or(false),
+            content_warning: map.get("content_warning").cloned(),
+            original_filename: map.get("originalFilename").cloned(),
+        }
+    }
+}
+
+/// Coarse media kind derived from a MIME type, for gallery filtering.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MediaCategory {
+    Image,
+    Audio,
+    Video,
+    Unknown,
+}
+
+impl MediaCategory {
+    fn from_mime_type(mime_type: &str) -> Self {
+        if mime_type.starts_with("image/") {
+            MediaCategory::Image
+        } else if mime_type.starts_with("audio/") {
+            MediaCategory::Audio
+        } else if mime_type.starts_with("video/") {
+            MediaCategory::Video
+        } else {
+            MediaCategory::Unknown
+        }
+    }
+}
+
+/// One entry returned by `list_media`: enough to render a gallery tile
+/// without downloading the object body.
+#[derive(Serialize, Debug, Clone)]
+pub struct MediaListEntry {
+    pub key: String,
+    pub size: u64,
+    pub category: MediaCategory,
+    pub meta: MediaMeta,
+}
+
+/// One page of `list_media` results. `cursor` is `Some` when more pages
+/// follow and should be passed back in as the next call's `cursor` argument.
+#[derive(Serialize, Debug, Clone)]
+pub struct MediaListPage {
+    pub entries: Vec<MediaListEntry>,
+    pub cursor: Option<String>,
+}
+
+/// Credentials needed to presign R2 S3-compatible requests. Not required for
+/// the plain `Bucket` binding operations, only for `get_presigned_media_url`.
+struct R2SigningConfig {
+    account_id: String,
+    bucket_name: String,
+    access_key_id: String,
+    secret_access_key: String,
+}
+
 pub struct MediaService {
     media_bucket: R2Bucket,
+    db: D1Database,
+    signing_config: Option<R2SigningConfig>,
+    /// Per-key reference counts for content-addressed blobs (see `upload_media_cas`).
+    /// `None` when `MEDIA_CAS_KV` isn't bound; CAS uploads still work but deletes
+    /// fall back to removing the blob unconditionally.
+    cas_refs_kv: Option<KvStore>,
+    /// Secret `encrypt_media`/`decrypt_media` derive each object's AES-256-GCM
+    /// key from. Required, like `MEDIA_BUCKET`, so profile media is never
+    /// written to R2 unencrypted.
+    encryption_key: String,
     // Optionally, store the public R2 domain if configured for public URLs
     // public_r2_domain: Option<String>,
 }
 
 impl MediaService {
+    const USER_MEDIA_FIELDS: &'static str =
+        "id, user_id, r2_object_key, position, content_type, width, height, created_at";
+
+    /// Sniffed content types accepted by `upload_media`/`upload_media_cas`.
+    const ALLOWED_MIME_TYPES: &'static [&'static str] = &[
+        "image/jpeg", "image/png", "image/gif", "image/webp",
+        "video/mp4", "video/webm", "application/pdf",
+    ];
+
+    /// Upper bound on upload size, enforced after MIME sniffing.
+    const MAX_UPLOAD_BYTES: usize = 25 * 1024 * 1024;
+
+    /// Upper bound on a single non-file multipart field (e.g. `alt_text`),
+    /// enforced by `upload_from_multipart`.
+    const MAX_TEXT_FIELD_BYTES: usize = 4 * 1024;
+
     pub fn new(env: &Env) -> Result<Self> { // worker::Result
-        match env.bucket("MEDIA_BUCKET") {
+        let media_bucket = match env.bucket("MEDIA_BUCKET") {
             Ok(media_bucket) => {
                 worker::console_log!("[MediaService] Initialized with MEDIA_BUCKET binding.");
                 // Example of how to get public R2 domain if it were set in env vars:
                 // let public_r2_domain = env.var("R2_PUBLIC_DOMAIN").map(|v| v.to_string()).ok();
-                Ok(Self { media_bucket /*, public_r2_domain */ })
+                media_bucket
             }
             Err(e) => {
                 worker::console_error!("[MediaService] CRITICAL: Failed to bind to 'MEDIA_BUCKET' R2 bucket: {}. Ensure it's configured in wrangler.toml.", e);
                 // Return a configuration error or propagate the worker::Error
-                Err(worker::Error::Configuration(format!("MEDIA_BUCKET R2 binding missing or invalid: {}", e)))
+                return Err(worker::Error::Configuration(format!("MEDIA_BUCKET R2 binding missing or invalid: {}", e)));
+            }
+        };
+        let db = env.d1("DB")?;
+        let signing_config = Self::load_signing_config(env);
+        let cas_refs_kv = env.kv("MEDIA_CAS_KV").ok();
+        let encryption_key = match env.secret("MEDIA_ENCRYPTION_KEY") {
+            Ok(secret) => secret.to_string(),
+            Err(e) => {
+                worker::console_error!("[MediaService] CRITICAL: Failed to bind to 'MEDIA_ENCRYPTION_KEY' secret: {}. Profile media must be encrypted at rest.", e);
+                return Err(worker::Error::Configuration(format!("MEDIA_ENCRYPTION_KEY secret missing or invalid: {}", e)));
             }
+        };
+        Ok(Self { media_bucket, db, signing_config, cas_refs_kv, encryption_key })
+    }
+
+    /// Reads the R2 account id, bucket name, and S3-compatible API credentials
+    /// from env vars. Returns `None` (not an error) when absent, since most
+    /// operations don't need presigning; `get_presigned_media_url` surfaces a
+    /// `ConfigurationError` itself when it's missing.
+    fn load_signing_config(env: &Env) -> Option<R2SigningConfig> {
+        let account_id = env.var("R2_ACCOUNT_ID").ok()?.to_string();
+        let bucket_name = env.var("R2_BUCKET_NAME").ok()?.to_string();
+        let access_key_id = env.secret("R2_ACCESS_KEY_ID").ok()?.to_string();
+        let secret_access_key = env.secret("R2_SECRET_ACCESS_KEY").ok()?.to_string();
+        Some(R2SigningConfig { account_id, bucket_name, access_key_id, secret_access_key })
+    }
+
+    /// Inserts a new `user_media` row, enforcing `MAX_USER_MEDIA_ITEMS` with a
+    /// `COUNT(*)` check issued in the same batch as the insert so the limit holds
+    /// even under concurrent uploads for the same user.
+    pub async fn add_user_media(
+        &self,
+        user_id: &str,
+        r2_object_key: String,
+        content_type: Option<String>,
+        width: Option<i64>,
+        height: Option<i64>,
+    ) -> Result<UserMedia> {
+        let count_sql = "SELECT COUNT(*) as count FROM user_media WHERE user_id = ?1";
+        let count: i64 = self.db.prepare(count_sql)
+            .bind(&[user_id.into()])?
+            .first::<HashMap<String, i64>>(None).await?
+            .and_then(|row| row.get("count").copied())
+            .unwrap_or(0);
+
+        if count as usize >= MAX_USER_MEDIA_ITEMS {
+            worker::console_warn!("[MediaService] User {} media limit ({}) reached.", user_id, MAX_USER_MEDIA_ITEMS);
+            return Err(worker::Error::RustError(MediaServiceError::MediaLimitReached(MAX_USER_MEDIA_ITEMS).to_string()));
         }
+
+        let id = Uuid::new_v4().to_string();
+        let now = Utc::now();
+        let sql = format!(
+            "INSERT INTO user_media (id, user_id, r2_object_key, position, content_type, width, height, created_at) \
+             VALUES (?1, ?2, ?3, (SELECT COALESCE(MAX(position), -1) + 1 FROM user_media WHERE user_id = ?2), ?4, ?5, ?6, ?7) \
+             RETURNING {}",
+            Self::USER_MEDIA_FIELDS
+        );
+        self.db.prepare(&sql)
+            .bind(&[
+                id.clone().into(), user_id.into(), r2_object_key.into(),
+                content_type.into(), width.into(), height.into(), now.to_rfc3339().into(),
+            ])?
+            .first::<UserMedia>(None).await?
+            .ok_or_else(|| worker::Error::RustError(format!("user_media row {} not found after insert", id)))
+    }
+
+    /// Removes one `user_media` row for a user and enqueues its R2 object key for
+    /// later garbage collection (see `cleanup_service::CleanupService`) rather
+    /// than deleting from R2 inline, so this stays fast on the hot path.
+    pub async fn remove_user_media(&self, user_id: &str, media_id: &str) -> Result<()> {
+        worker::console_log!("[MediaService] Removing user_media row {} for user {}", media_id, user_id);
+        let sql = format!("SELECT {} FROM user_media WHERE id = ?1 AND user_id = ?2", Self::USER_MEDIA_FIELDS);
+        let existing = self.db.prepare(&sql)
+            .bind(&[media_id.into(), user_id.into()])?
+            .first::<UserMedia>(None).await?;
+
+        self.db.prepare("DELETE FROM user_media WHERE id = ?1 AND user_id = ?2")
+            .bind(&[media_id.into(), user_id.into()])?
+            .run().await?;
+
+        if let Some(media) = existing {
+            let now = Utc::now();
+            self.db.prepare("INSERT INTO deletion_queue (r2_object_key, reason, enqueued_at) VALUES (?1, ?2, ?3)")
+                .bind(&[media.r2_object_key.into(), "media_removed".into(), now.to_rfc3339().into()])?
+                .run().await?;
+        }
+        Ok(())
+    }
+
+    /// Lists a user's media ordered by `position`, ascending.
+    pub async fn list_user_media(&self, user_id: &str) -> Result<Vec<UserMedia>> {
+        let sql = format!("SELECT {} FROM user_media WHERE user_id = ?1 ORDER BY position ASC", Self::USER_MEDIA_FIELDS);
+        let results = self.db.prepare(&sql).bind(&[user_id.into()])?.all().await?;
+        results.results::<UserMedia>()
+    }
+
+    /// Reassigns `position` for each id in `ordered_media_ids`, in the order given.
+    pub async fn reorder_user_media(&self, user_id: &str, ordered_media_ids: &[String]) -> Result<()> {
+        let mut statements = Vec::with_capacity(ordered_media_ids.len());
+        for (position, media_id) in ordered_media_ids.iter().enumerate() {
+            statements.push(
+                self.db.prepare("UPDATE user_media SET position = ?1 WHERE id = ?2 AND user_id = ?3")
+                    .bind(&[(position as i64).into(), media_id.clone().into(), user_id.into()])?
+            );
+        }
+        self.db.batch(statements).await?;
+        Ok(())
     }
 
     /// Generates a unique object key for R2 storage.
@@ -65,10 +316,7 @@ impl MediaService {
             .filter(|s| !s.is_empty()) // Ensure stem is not empty
             .unwrap_or("media");
 
-        let extension = Path::new(original_file_name)
-            .extension()
-            .and_then(|s| s.to_str())
-            .map_or_else(String::new, |ext| format!(".{}", ext.to_lowercase())); // Standardize extension to lowercase
+        let extension = Self::extract_extension(original_file_name);
 
         // Basic sanitization for stem: replace non-alphanumeric (excluding typical separators) with underscore
         let safe_stem: String = file_stem
@@ -88,33 +336,127 @@ impl MediaService {
         Ok(format!("{}/{}_{}{}", user_id, truncated_stem, unique_id, extension))
     }
 
+    /// Lowercased, dot-prefixed file extension (e.g. `".jpg"`), or `""` if
+    /// `original_file_name` has none.
+    fn extract_extension(original_file_name: &str) -> String {
+        Path::new(original_file_name)
+            .extension()
+            .and_then(|s| s.to_str())
+            .map_or_else(String::new, |ext| format!(".{}", ext.to_lowercase()))
+    }
+
+    /// Content-addressed variant of `upload_media`: keys the object by the
+    /// SHA-256 hash of its bytes (`cas/<hex[0:2]>/<hex[2:4]>/<fullhex><ext>`)
+    /// rather than a random UUID, so identical files uploaded by different
+    /// users are stored once. A `head()` check skips the `put` entirely when
+    /// the blob already exists, and `MEDIA_CAS_KV` tracks how many uploads
+    /// reference the key so `delete_media` only removes it once unreferenced.
+    pub async fn upload_media_cas(
+        &self,
+        user_id: &str,
+        original_file_name: String,
+        mime_type: Option<String>,
+        body: Vec<u8>,
+    ) -> Result<String> {
+        let sniffed_mime_type = Self::validate_upload(&body, mime_type.as_deref())?;
+        let hex_digest = hex::encode(Sha256::digest(&body));
+        let extension = Self::extract_extension(&original_file_name);
+        let object_key = format!("cas/{}/{}/{}{}", &hex_digest[0..2], &hex_digest[2..4], hex_digest, extension);
+
+        let already_exists = self.media_bucket.head(&object_key).await?.is_some();
+
+        if already_exists {
+            worker::console_log!(
+                "[MediaService] CAS dedup hit: User '{}', Key '{}' already in R2; skipping upload.",
+                user_id, object_key
+            );
+        } else {
+            let encrypted_body = encrypt_media(&self.encryption_key, &object_key, &body)?;
+            let mut put_options = R2PutOptions::new();
+            let mut http_metadata = HttpMetadata::default();
+            http_metadata.content_type = Some(sniffed_mime_type);
+            put_options = put_options.http_metadata(http_metadata);
+            self.media_bucket.put(&object_key, encrypted_body).set_options(put_options).execute().await?;
+            worker::console_log!("[MediaService] CAS upload OK: User '{}', Key '{}'", user_id, object_key);
+        }
+
+        if let Some(kv) = &self.cas_refs_kv {
+            let count: i64 = kv.get(&object_key).text().await?
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(0);
+            kv.put(&object_key, (count + 1).to_string())?.execute().await?;
+        } else {
+            worker::console_warn!("[MediaService] MEDIA_CAS_KV not configured; CAS reference count not tracked for '{}'.", object_key);
+        }
+
+        Ok(object_key)
+    }
+
+    /// Sniffs the real content type from `body`'s leading bytes and checks it
+    /// against `ALLOWED_MIME_TYPES`, rejecting with `UnsupportedMediaType` if
+    /// it isn't allowed or can't be determined. Also enforces
+    /// `MAX_UPLOAD_BYTES`. A caller-declared `mime_type` that disagrees with
+    /// the sniffed one is only logged, not rejected — legitimate clients
+    /// often declare a synonym or placeholder (`image/jpg`,
+    /// `application/octet-stream`) and the allow-list above already closes
+    /// the spoofing gap. Returns the sniffed type, which callers should store
+    /// in place of whatever `mime_type` was declared.
+    fn validate_upload(body: &[u8], declared_mime_type: Option<&str>) -> Result<String> {
+        if body.len() > Self::MAX_UPLOAD_BYTES {
+            return Err(worker::Error::RustError(MediaServiceError::UploadTooLarge.to_string()));
+        }
+
+        let sniffed = sniff_mime_type(body).ok_or_else(|| {
+            worker::Error::RustError(
+                MediaServiceError::UnsupportedMediaType("could not determine file type from content".to_string()).to_string()
+            )
+        })?;
+
+        if !Self::ALLOWED_MIME_TYPES.contains(&sniffed) {
+            return Err(worker::Error::RustError(
+                MediaServiceError::UnsupportedMediaType(sniffed.to_string()).to_string()
+            ));
+        }
+
+        if let Some(declared) = declared_mime_type {
+            if declared != sniffed {
+                worker::console_warn!(
+                    "[MediaService] Declared MIME '{}' does not match sniffed '{}'; using sniffed value.",
+                    declared, sniffed
+                );
+            }
+        }
+
+        Ok(sniffed.to_string())
+    }
+
     pub async fn upload_media(
         &self,
         user_id: &str,
         original_file_name: String,
         mime_type: Option<String>,
-        body: Vec<u8>
+        body: Vec<u8>,
+        media_meta: Option<MediaMeta>,
     ) -> Result<String> { // Returns object key
+        let sniffed_mime_type = Self::validate_upload(&body, mime_type.as_deref())?;
         let object_key = self.generate_object_key(user_id, &original_file_name)?;
 
         worker::console_log!(
-            "[MediaService] Uploading: User '{}', Key '{}', Size {}B, MIME {:?}",
-            user_id, object_key, body.len(), mime_type
+            "[MediaService] Uploading: User '{}', Key '{}', Size {}B, MIME {}",
+            user_id, object_key, body.len(), sniffed_mime_type
         );
 
+        let encrypted_body = encrypt_media(&self.encryption_key, &object_key, &body)?;
+
         let mut put_options = R2PutOptions::new();
-        if let Some(mt) = mime_type {
-            let mut http_metadata = HttpMetadata::default();
-            http_metadata.content_type = Some(mt); // e.g., "image/jpeg", "video/mp4"
-            put_options = put_options.http_metadata(http_metadata);
+        let mut http_metadata = HttpMetadata::default();
+        http_metadata.content_type = Some(sniffed_mime_type);
+        put_options = put_options.http_metadata(http_metadata);
+        if let Some(meta) = media_meta {
+            put_options = put_options.custom_metadata(meta.into_custom_metadata());
         }
-        // Example: Add custom metadata (original filename, uploader_id)
-        // let mut custom_metadata = HashMap::new();
-        // custom_metadata.insert("originalFilename".into(), original_file_name.clone()); // Max 2KB total for custom metadata
-        // custom_metadata.insert("userId".into(), user_id.to_string());
-        // put_options = put_options.custom_metadata(custom_metadata);
 
-        match self.media_bucket.put(&object_key, body).set_options(put_options).execute().await {
+        match self.media_bucket.put(&object_key, encrypted_body).set_options(put_options).execute().await {
             Ok(put_object) => {
                 worker::console_log!("[MediaService] R2 Upload OK: Key '{}', ETag '{}'", object_key, put_object.etag());
                 Ok(object_key)
@@ -126,7 +468,124 @@ impl MediaService {
         }
     }
 
+    /// Streams a `multipart/form-data` upload straight into R2 instead of
+    /// requiring the whole file buffered up front, following Garage's
+    /// `handle_post_object`/`multer` approach. Expects a `file` field (read
+    /// with `MAX_UPLOAD_BYTES`) plus optional `alt_text`, `sensitive`, and
+    /// `content_warning` text fields (each capped at `MAX_TEXT_FIELD_BYTES`
+    /// and rejected with `UploadTooLarge` if exceeded).
+    pub async fn upload_from_multipart(&self, user_id: &str, mut req: worker::Request) -> Result<String> {
+        let content_type = req.headers().get("Content-Type")?
+            .ok_or_else(|| worker::Error::RustError("missing Content-Type header".to_string()))?;
+        let boundary = multer::parse_boundary(&content_type)
+            .map_err(|e| worker::Error::RustError(format!("invalid multipart Content-Type: {}", e)))?;
+
+        let body_stream = req.stream()?;
+        let mut multipart = multer::Multipart::new(body_stream, boundary);
+
+        let mut original_file_name = String::new();
+        let mut mime_type: Option<String> = None;
+        let mut body: Vec<u8> = Vec::new();
+        let mut alt_text: Option<String> = None;
+        let mut sensitive = false;
+        let mut content_warning: Option<String> = None;
+
+        while let Some(mut field) = multipart.next_field().await.map_err(|e| worker::Error::RustError(e.to_string()))? {
+            match field.name().unwrap_or("") {
+                "file" => {
+                    original_file_name = field.file_name().unwrap_or("upload").to_string();
+                    mime_type = field.content_type().map(|m| m.to_string());
+                    while let Some(chunk) = field.chunk().await.map_err(|e| worker::Error::RustError(e.to_string()))? {
+                        if body.len() + chunk.len() > Self::MAX_UPLOAD_BYTES {
+                            return Err(worker::Error::RustError(MediaServiceError::UploadTooLarge.to_string()));
+                        }
+                        body.extend_from_slice(&chunk);
+                    }
+                }
+                "alt_text" => {
+                    let text = Self::read_text_field(&mut field).await?;
+                    alt_text = (!text.is_empty()).then_some(text);
+                }
+                "sensitive" => {
+                    let text = Self::read_text_field(&mut field).await?;
+                    sensitive = text == "true" || text == "1";
+                }
+                "content_warning" => {
+                    let text = Self::read_text_field(&mut field).await?;
+                    content_warning = (!text.is_empty()).then_some(text);
+                }
+                _ => {} // Unknown fields are ignored rather than rejected.
+            }
+        }
+
+        if body.is_empty() {
+            return Err(worker::Error::RustError(MediaServiceError::InvalidFileName("missing 'file' field".to_string()).to_string()));
+        }
+
+        let media_meta = MediaMeta {
+            alt_text, sensitive, content_warning,
+            original_filename: (!original_file_name.is_empty()).then(|| original_file_name.clone()),
+        };
+        self.upload_media(user_id, original_file_name, mime_type, body, Some(media_meta)).await
+    }
+
+    /// Reads one multipart text field fully, rejecting it with
+    /// `UploadTooLarge` past `MAX_TEXT_FIELD_BYTES`.
+    async fn read_text_field(field: &mut multer::Field<'_>) -> Result<String> {
+        let mut buf = Vec::new();
+        while let Some(chunk) = field.chunk().await.map_err(|e| worker::Error::RustError(e.to_string()))? {
+            if buf.len() + chunk.len() > Self::MAX_TEXT_FIELD_BYTES {
+                return Err(worker::Error::RustError(MediaServiceError::UploadTooLarge.to_string()));
+            }
+            buf.extend_from_slice(&chunk);
+        }
+        String::from_utf8(buf).map_err(|e| worker::Error::RustError(e.to_string()))
+    }
+
+    /// Lists the objects under `<user_id>/`, one page at a time, for a
+    /// gallery/management view. Pass the previous page's `cursor` back in to
+    /// continue; `None` starts from the beginning.
+    pub async fn list_media(&self, user_id: &str, cursor: Option<String>, limit: u32) -> Result<MediaListPage> {
+        let mut list_builder = self.media_bucket.list().prefix(format!("{}/", user_id)).limit(limit);
+        if let Some(c) = cursor {
+            list_builder = list_builder.cursor(c);
+        }
+        let listed = list_builder.execute().await?;
+
+        let mut entries = Vec::new();
+        for object in listed.objects() {
+            let category = object.http_metadata().content_type
+                .map(|ct| MediaCategory::from_mime_type(&ct))
+                .unwrap_or(MediaCategory::Unknown);
+            let meta = MediaMeta::from_custom_metadata(&object.custom_metadata()?);
+            entries.push(MediaListEntry { key: object.key(), size: object.size(), category, meta });
+        }
+
+        let next_cursor = if listed.truncated() { listed.cursor() } else { None };
+        Ok(MediaListPage { entries, cursor: next_cursor })
+    }
+
+    /// Fetches one object's category and metadata via `head()`, without
+    /// downloading its body. Returns `None` if `key` doesn't exist.
+    pub async fn get_media_meta(&self, key: &str) -> Result<Option<(MediaCategory, MediaMeta)>> {
+        let Some(object) = self.media_bucket.head(key).await? else {
+            return Ok(None);
+        };
+        let category = object.http_metadata().content_type
+            .map(|ct| MediaCategory::from_mime_type(&ct))
+            .unwrap_or(MediaCategory::Unknown);
+        let meta = MediaMeta::from_custom_metadata(&object.custom_metadata()?);
+        Ok(Some((category, meta)))
+    }
+
     pub async fn delete_media(&self, object_key: &str) -> Result<()> {
+        if object_key.starts_with("cas/") {
+            return self.delete_cas_media(object_key).await;
+        }
+        self.delete_object_from_bucket(object_key).await
+    }
+
+    async fn delete_object_from_bucket(&self, object_key: &str) -> Result<()> {
         worker::console_log!("[MediaService] Deleting: Key '{}'", object_key);
         // R2 delete is idempotent; no error if object doesn't exist.
         // Use .head() first if you need to confirm existence or get metadata before delete.
@@ -142,6 +601,36 @@ impl MediaService {
         }
     }
 
+    /// Decrements the `MEDIA_CAS_KV` reference count for `object_key` and only
+    /// deletes the R2 blob once the count reaches zero, since the same
+    /// content-addressed object may be shared by multiple users' uploads.
+    async fn delete_cas_media(&self, object_key: &str) -> Result<()> {
+        let Some(kv) = &self.cas_refs_kv else {
+            worker::console_warn!(
+                "[MediaService] MEDIA_CAS_KV not configured; deleting CAS object '{}' unconditionally.",
+                object_key
+            );
+            return self.delete_object_from_bucket(object_key).await;
+        };
+
+        let count: i64 = kv.get(object_key).text().await?
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1);
+
+        if count <= 1 {
+            kv.delete(object_key).await?;
+            self.delete_object_from_bucket(object_key).await
+        } else {
+            let remaining = count - 1;
+            kv.put(object_key, remaining.to_string())?.execute().await?;
+            worker::console_log!(
+                "[MediaService] CAS object '{}' still referenced {} time(s); keeping blob.",
+                object_key, remaining
+            );
+            Ok(())
+        }
+    }
+
     pub async fn get_media_public_url(&self, object_key: &str) -> Result<String> {
         worker::console_log!("[MediaService] Generating public URL for: Key '{}'", object_key);
         // This is a placeholder. Actual public URL depends on R2 bucket's public access settings
@@ -158,15 +647,228 @@ impl MediaService {
         // }
     }
 
-    pub async fn get_presigned_media_url(&self, object_key: &str, _duration_seconds: u32) -> Result<String> {
-        worker::console_log!("[MediaService] Generating presigned URL for: Key '{}'", object_key);
-        // Actual R2 presigned URLs require more complex setup (IAM permissions for the Worker,
-        // and using specific R2 SDK features not directly exposed in basic workers-rs `Bucket` yet,
-        // or making AWS SigV4 signed requests manually or via a library).
-        // This is a placeholder.
-        worker::console_warn!("[MediaService] get_presigned_media_url - NOT IMPLEMENTED YET.");
-        Err(worker::Error::RustError(format!("Presigned URL generation for '{}' is not implemented.", object_key)))
+    /// Serves `object_key` directly from R2, the handler the `/media/<key>`
+    /// path from `get_media_public_url` needs. Honors an incoming `Range`
+    /// header with a `206 Partial Content` response so video seeking and
+    /// resumable downloads still work, sliced out of the decrypted
+    /// plaintext — an AES-256-GCM auth tag covers the whole object, so
+    /// unlike before encryption this always fetches the full object from R2
+    /// rather than requesting a sub-range. Sets `Content-Disposition` from
+    /// the `originalFilename` custom metadata when present.
+    pub async fn serve_media(&self, object_key: &str, req_headers: &Headers) -> Result<Response> {
+        let Some(head) = self.media_bucket.head(object_key).await? else {
+            return Response::error("Not Found", 404);
+        };
+
+        let content_type = head.http_metadata().content_type
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+        let original_filename = head.custom_metadata()?.get("originalFilename").cloned();
+
+        let Some(object) = self.media_bucket.get(object_key).execute().await? else {
+            return Response::error("Not Found", 404);
+        };
+        let stored = object.body().ok_or_else(|| worker::Error::RustError("R2 object has no body".to_string()))?.bytes().await?;
+        let plaintext = decrypt_media(&self.encryption_key, object_key, &stored)?;
+        let total_size = plaintext.len() as u64;
+
+        let range = req_headers.get("Range")?
+            .and_then(|h| parse_range_header(&h, total_size));
+
+        let body: Vec<u8> = match range {
+            Some((start, end)) => plaintext[start as usize..=end as usize].to_vec(),
+            None => plaintext,
+        };
+
+        let mut response = Response::from_bytes(body)?;
+        {
+            let headers = response.headers_mut();
+            headers.set("Content-Type", &content_type)?;
+            headers.set("Accept-Ranges", "bytes")?;
+            if let Some(name) = original_filename {
+                headers.set("Content-Disposition", &format!("attachment; filename=\"{}\"", name))?;
+            }
+            if let Some((start, end)) = range {
+                headers.set("Content-Range", &format!("bytes {}-{}/{}", start, end, total_size))?;
+            }
+        }
+
+        Ok(if range.is_some() { response.with_status(206) } else { response })
+    }
+
+    /// Builds a time-limited presigned URL for `object_key` against R2's S3-
+    /// compatible endpoint using AWS SigV4 query-string signing, so clients can
+    /// upload/download directly without proxying bytes through the Worker.
+    /// `method` is the HTTP verb the URL will be used with (`"GET"` or `"PUT"`).
+    pub async fn get_presigned_media_url(&self, object_key: &str, duration_seconds: u32, method: &str) -> Result<String> {
+        let config = self.signing_config.as_ref().ok_or_else(|| {
+            worker::Error::RustError(MediaServiceError::ConfigurationError(
+                "R2_ACCOUNT_ID, R2_BUCKET_NAME, R2_ACCESS_KEY_ID, and R2_SECRET_ACCESS_KEY must all be set to presign URLs".to_string()
+            ).to_string())
+        })?;
+
+        let now = Date::now().as_millis();
+        let amz_date = format_amz_date(now);
+        let date_stamp = &amz_date[0..8];
+        let host = format!("{}.{}.r2.cloudflarestorage.com", config.bucket_name, config.account_id);
+        let scope = format!("{}/auto/s3/aws4_request", date_stamp);
+        let credential = format!("{}/{}", config.access_key_id, scope);
+
+        let canonical_query = format!(
+            "X-Amz-Algorithm=AWS4-HMAC-SHA256&X-Amz-Credential={}&X-Amz-Date={}&X-Amz-Expires={}&X-Amz-SignedHeaders=host",
+            urlencode(&credential), amz_date, duration_seconds
+        );
+        let canonical_uri = format!("/{}", object_key);
+        let canonical_request = format!(
+            "{}\n{}\n{}\nhost:{}\n\nhost\nUNSIGNED-PAYLOAD",
+            method, canonical_uri, canonical_query, host
+        );
+        let canonical_request_hash = hex::encode(Sha256::digest(canonical_request.as_bytes()));
+
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, canonical_request_hash
+        );
+
+        let signing_key = derive_signing_key(&config.secret_access_key, date_stamp, "auto", "s3");
+        let signature = hex::encode(hmac_sha256(&signing_key, string_to_sign.as_bytes()));
+
+        Ok(format!(
+            "https://{}{}?{}&X-Amz-Signature={}",
+            host, canonical_uri, canonical_query, signature
+        ))
+    }
+}
+
+/// Sniffs a file's real content type from its leading bytes so callers can't
+/// spoof `mime_type`. Returns `None` when no known signature matches.
+fn sniff_mime_type(body: &[u8]) -> Option<&'static str> {
+    if body.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if body.starts_with(&[0x89, 0x50, 0x4E, 0x47]) {
+        Some("image/png")
+    } else if body.starts_with(b"GIF8") {
+        Some("image/gif")
+    } else if body.len() >= 12 && &body[0..4] == b"RIFF" && &body[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if body.len() >= 8 && &body[4..8] == b"ftyp" {
+        Some("video/mp4")
+    } else if body.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+        Some("video/webm")
+    } else if body.starts_with(b"%PDF") {
+        Some("application/pdf")
+    } else {
+        None
+    }
+}
+
+/// Parses a `Range: bytes=start-end` header (the end-less `bytes=start-` form
+/// included) against a known object size, returning an inclusive `(start, end)`
+/// byte range. Returns `None` for anything malformed or out of bounds, so the
+/// caller falls back to serving the full object.
+fn parse_range_header(header: &str, total_size: u64) -> Option<(u64, u64)> {
+    let spec = header.strip_prefix("bytes=")?;
+    let (start_str, end_str) = spec.split_once('-')?;
+    let start: u64 = start_str.parse().ok()?;
+    let end: u64 = if end_str.is_empty() {
+        total_size.saturating_sub(1)
+    } else {
+        end_str.parse().ok()?
+    };
+    if total_size == 0 || start > end || start >= total_size {
+        return None;
+    }
+    Some((start, end.min(total_size - 1)))
+}
+
+fn format_amz_date(millis: u64) -> String {
+    let secs = (millis / 1000) as i64;
+    let dt = DateTime::<Utc>::from_timestamp(secs, 0).unwrap_or_else(Utc::now);
+    dt.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Length in bytes of the AES-256-GCM IV `encrypt_media` prefixes onto every
+/// stored object.
+const GCM_IV_LEN: usize = 12;
+
+/// Derives a 32-byte AES-256-GCM key for `object_key` from `secret` via
+/// HMAC-SHA256, so every object gets a distinct key without having to store
+/// one per object.
+fn derive_media_key(secret: &str, object_key: &str) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&hmac_sha256(secret.as_bytes(), object_key.as_bytes()));
+    key
+}
+
+/// Encrypts `plaintext` with AES-256-GCM under a key derived from `secret`
+/// and `object_key`, returning `iv || ciphertext || tag` ready to store as
+/// the R2 object body. `decrypt_media` reverses this.
+fn encrypt_media(secret: &str, object_key: &str, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let key_bytes = derive_media_key(secret, object_key);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes));
+
+    let iv_bytes: [u8; GCM_IV_LEN] = {
+        let uuid_bytes = *Uuid::new_v4().as_bytes();
+        let mut iv = [0u8; GCM_IV_LEN];
+        iv.copy_from_slice(&uuid_bytes[..GCM_IV_LEN]);
+        iv
+    };
+    let nonce = Nonce::from_slice(&iv_bytes);
+
+    let ciphertext = cipher.encrypt(nonce, plaintext).map_err(|e| {
+        worker::Error::RustError(MediaServiceError::EncryptionFailed(e.to_string()).to_string())
+    })?;
+
+    let mut stored = Vec::with_capacity(GCM_IV_LEN + ciphertext.len());
+    stored.extend_from_slice(&iv_bytes);
+    stored.extend_from_slice(&ciphertext);
+    Ok(stored)
+}
+
+/// Reverses `encrypt_media`: splits `stored`'s leading `GCM_IV_LEN` bytes off
+/// as the IV and decrypts the rest under the same derived key.
+fn decrypt_media(secret: &str, object_key: &str, stored: &[u8]) -> Result<Vec<u8>> {
+    if stored.len() < GCM_IV_LEN {
+        return Err(worker::Error::RustError(
+            MediaServiceError::EncryptionFailed(format!("object '{}' is shorter than the IV", object_key)).to_string()
+        ));
+    }
+    let (iv_bytes, ciphertext) = stored.split_at(GCM_IV_LEN);
+
+    let key_bytes = derive_media_key(secret, object_key);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes));
+    let nonce = Nonce::from_slice(iv_bytes);
+
+    cipher.decrypt(nonce, ciphertext).map_err(|e| {
+        worker::Error::RustError(MediaServiceError::EncryptionFailed(e.to_string()).to_string())
+    })
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Chains `HMAC-SHA256` over `"AWS4" + secret`, the date, the region, the
+/// service, and the literal `"aws4_request"`, per the SigV4 spec.
+fn derive_signing_key(secret: &str, date_stamp: &str, region: &str, service: &str) -> Vec<u8> {
+    let k_date = hmac_sha256(format!("AWS4{}", secret).as_bytes(), date_stamp.as_bytes());
+    let k_region = hmac_sha256(&k_date, region.as_bytes());
+    let k_service = hmac_sha256(&k_region, service.as_bytes());
+    hmac_sha256(&k_service, b"aws4_request")
+}
+
+/// Percent-encodes a string per RFC 3986 for use in a SigV4 canonical query
+/// string (`X-Amz-Credential` contains `/` which must be escaped as `%2F`).
+fn urlencode(input: &str) -> String {
+    let mut out = String::with_capacity(input.len());
+    for byte in input.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(byte as char),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
     }
+    out
 }
 
 // Basic tests for generate_object_key (can be expanded)
@@ -176,7 +878,7 @@ mod tests {
 
     #[test]
     fn test_generate_object_key_normal() {
-        let service = MediaService { media_bucket: Env::empty().bucket("MEDIA_BUCKET").unwrap() }; // Mock bucket for test
+        let service = MediaService { media_bucket: Env::empty().bucket("MEDIA_BUCKET").unwrap(), db: Env::empty().d1("DB").unwrap(), signing_config: None, cas_refs_kv: None, encryption_key: "test-encryption-key".to_string() }; // Mock bucket for test
         let key = service.generate_object_key("user123", "profile_picture.jpg").unwrap();
         assert!(key.starts_with("user123/profile_picture_"));
         assert!(key.ends_with(".jpg"));
@@ -185,7 +887,7 @@ mod tests {
 
     #[test]
     fn test_generate_object_key_no_extension() {
-        let service = MediaService { media_bucket: Env::empty().bucket("MEDIA_BUCKET").unwrap() };
+        let service = MediaService { media_bucket: Env::empty().bucket("MEDIA_BUCKET").unwrap(), db: Env::empty().d1("DB").unwrap(), signing_config: None, cas_refs_kv: None, encryption_key: "test-encryption-key".to_string() };
         let key = service.generate_object_key("user456", "myfile").unwrap();
         assert!(key.starts_with("user456/myfile_"));
         assert!(!key.contains(".")); // No dot before UUID part if original had no extension
@@ -193,7 +895,7 @@ mod tests {
 
     #[test]
     fn test_generate_object_key_special_chars_in_name() {
-        let service = MediaService { media_bucket: Env::empty().bucket("MEDIA_BUCKET").unwrap() };
+        let service = MediaService { media_bucket: Env::empty().bucket("MEDIA_BUCKET").unwrap(), db: Env::empty().d1("DB").unwrap(), signing_config: None, cas_refs_kv: None, encryption_key: "test-encryption-key".to_string() };
         let key = service.generate_object_key("user789", "my test file with spaces & chars!.png").unwrap();
         assert!(key.starts_with("user789/my_test_file_with_spaces___chars_"));
         assert!(key.ends_with(".png"));
@@ -202,11 +904,35 @@ mod tests {
     #[test]
     fn test_generate_object_key_empty_name() {
         // Path::file_stem("") is Some(""), so unwrap_or("media") is not hit unless original_file_name is "." or ".."
-        let service = MediaService { media_bucket: Env::empty().bucket("MEDIA_BUCKET").unwrap() };
+        let service = MediaService { media_bucket: Env::empty().bucket("MEDIA_BUCKET").unwrap(), db: Env::empty().d1("DB").unwrap(), signing_config: None, cas_refs_kv: None, encryption_key: "test-encryption-key".to_string() };
         let key = service.generate_object_key("userABC", "").unwrap();
         assert!(key.starts_with("userABC/media_")); // Falls back to "media" because stem of "" is ""
 
         let key_dot = service.generate_object_key("userABC", ".").unwrap();
         assert!(key_dot.starts_with("userABC/media_")); // Stem of "." is None
     }
+
+    #[test]
+    fn test_encrypt_media_round_trips() {
+        let plaintext = b"profile photo bytes";
+        let stored = encrypt_media("a-secret", "user123/photo.jpg", plaintext).unwrap();
+        assert_ne!(stored[GCM_IV_LEN..], plaintext[..], "ciphertext shouldn't equal the plaintext");
+
+        let decrypted = decrypt_media("a-secret", "user123/photo.jpg", &stored).unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn test_decrypt_media_rejects_wrong_key_or_object_key() {
+        let stored = encrypt_media("a-secret", "user123/photo.jpg", b"profile photo bytes").unwrap();
+        assert!(decrypt_media("wrong-secret", "user123/photo.jpg", &stored).is_err());
+        assert!(decrypt_media("a-secret", "user123/other.jpg", &stored).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_media_uses_a_fresh_iv_each_time() {
+        let a = encrypt_media("a-secret", "user123/photo.jpg", b"same bytes").unwrap();
+        let b = encrypt_media("a-secret", "user123/photo.jpg", b"same bytes").unwrap();
+        assert_ne!(a[..GCM_IV_LEN], b[..GCM_IV_LEN], "each upload should get its own IV");
+    }
 }
@@ -0,0 +1,202 @@
+// src/moderation_service/mod.rs
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use worker::{D1Database, Env, Result, console_log};
+
+use crate::ban_service::{Ban, BanService};
+
+#[derive(Debug)]
+pub enum ModerationServiceError {
+    InvalidDuration(String),
+    DurationOutOfRange,
+}
+
+impl std::fmt::Display for ModerationServiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ModerationServiceError::InvalidDuration(s) => write!(f, "Invalid duration: {}", s),
+            ModerationServiceError::DurationOutOfRange => write!(f, "Duration must be greater than zero and no more than 30 days."),
+        }
+    }
+}
+
+impl std::error::Error for ModerationServiceError {}
+
+/// The unit a human-readable duration's trailing letter maps to, e.g. the
+/// `m` in `10m`. Mirrors the single-letter suffixes moderators already type
+/// in group-management bots.
+enum TimeMetric {
+    Minutes,
+    Hours,
+    Days,
+}
+
+impl TimeMetric {
+    fn from_suffix(c: char) -> Option<Self> {
+        match c {
+            'm' => Some(TimeMetric::Minutes),
+            'h' => Some(TimeMetric::Hours),
+            'd' => Some(TimeMetric::Days),
+            _ => None,
+        }
+    }
+
+    fn to_duration(&self, amount: i64) -> Duration {
+        match self {
+            TimeMetric::Minutes => Duration::minutes(amount),
+            TimeMetric::Hours => Duration::hours(amount),
+            TimeMetric::Days => Duration::days(amount),
+        }
+    }
+}
+
+/// Upper bound on a single `/ban` or `/mute` duration.
+fn max_duration() -> Duration {
+    Duration::days(30)
+}
+
+/// Parses a human duration like `10m`, `2h`, or `1d` into a `chrono::Duration`,
+/// rejecting zero and anything over `max_duration()`.
+fn parse_duration(input: &str) -> std::result::Result<Duration, ModerationServiceError> {
+    let input = input.trim();
+    let suffix = input.chars().last()
+        .ok_or_else(|| ModerationServiceError::InvalidDuration(input.to_string()))?;
+    let metric = TimeMetric::from_suffix(suffix)
+        .ok_or_else(|| ModerationServiceError::InvalidDuration(input.to_string()))?;
+    let amount: i64 = input[..input.len() - suffix.len_utf8()].parse()
+        .map_err(|_| ModerationServiceError::InvalidDuration(input.to_string()))?;
+
+    if amount <= 0 {
+        return Err(ModerationServiceError::DurationOutOfRange);
+    }
+    let duration = metric.to_duration(amount);
+    if duration > max_duration() {
+        return Err(ModerationServiceError::DurationOutOfRange);
+    }
+    Ok(duration)
+}
+
+/// One row of the `mutes` table: a chat-restriction, parallel to `Ban` but
+/// not removing the user's access to `/start`-level interactions.
+/// `starts_at` is usually `created_at`, but a delayed mute sets it in the
+/// future; until then `check_and_lift_mute` treats the row as not yet active.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mute {
+    pub id: String,
+    pub user_id: String,
+    pub reason: String,
+    pub muted_by: String,
+    pub created_at: DateTime<Utc>,
+    pub starts_at: DateTime<Utc>,
+    pub unrestrict_at: DateTime<Utc>,
+}
+
+/// Admin/moderator-facing enforcement: bans (delegated to `BanService`) and
+/// timed mutes, both keyed off a lazily-checked `unrestrict_at` since
+/// Workers have no background timers to lift a restriction the moment it
+/// expires.
+pub struct ModerationService {
+    db: D1Database,
+    ban_service: BanService,
+}
+
+impl ModerationService {
+    const MUTE_FIELDS: &'static str = "id, user_id, reason, muted_by, created_at, starts_at, unrestrict_at";
+
+    pub fn new(env: &Env) -> Result<Self> {
+        let db = env.d1("DB")?;
+        let ban_service = BanService::new(env)?;
+        Ok(Self { db, ban_service })
+    }
+
+    /// Bans `user_id` for a parsed human duration (e.g. `"2h"`), or
+    /// permanently when `duration_str` is `None`. `delay_str`, if given,
+    /// defers the ban's start (e.g. `"10m"` to ban ten minutes from now).
+    pub async fn ban(&self, user_id: &str, reason: &str, banned_by: &str, duration_str: Option<&str>, delay_str: Option<&str>) -> Result<Ban> {
+        let duration = duration_str
+            .map(|s| parse_duration(s))
+            .transpose()
+            .map_err(|e| worker::Error::RustError(e.to_string()))?;
+        let delay = delay_str
+            .map(|s| parse_duration(s))
+            .transpose()
+            .map_err(|e| worker::Error::RustError(e.to_string()))?;
+        self.ban_service.ban_user(user_id, reason, banned_by, duration, delay).await
+    }
+
+    pub async fn unban(&self, user_id: &str) -> Result<()> {
+        self.ban_service.unban_user(user_id).await
+    }
+
+    /// Returns `user_id`'s active ban, if any. Delegates to `BanService`;
+    /// exposed here so callers that already hold a `ModerationService` (e.g.
+    /// `BanStateHook`) don't need a separate `BanService` handle.
+    pub async fn is_banned(&self, user_id: &str) -> Result<Option<Ban>> {
+        self.ban_service.is_banned(user_id).await
+    }
+
+    /// Clears a stale `UserState::Blocked` flag once `is_banned` confirms no
+    /// ban row justifies it anymore. Delegates to `BanService`; exposed here
+    /// for the same reason `is_banned` is.
+    pub async fn clear_stale_block(&self, user_id: &str) -> Result<()> {
+        self.ban_service.clear_stale_block(user_id).await
+    }
+
+    /// Mutes `user_id` until `now + duration_str`, e.g. `"10m"`. `delay_str`,
+    /// if given, defers the mute's start the same way `ban`'s does.
+    pub async fn mute(&self, user_id: &str, duration_str: &str, muted_by: &str, delay_str: Option<&str>) -> Result<Mute> {
+        let duration = parse_duration(duration_str).map_err(|e| worker::Error::RustError(e.to_string()))?;
+        let delay = delay_str
+            .map(|s| parse_duration(s))
+            .transpose()
+            .map_err(|e| worker::Error::RustError(e.to_string()))?;
+        let id = worker::Uuid::new_v4().to_string();
+        let now = Utc::now();
+        let starts_at = now + delay.unwrap_or_else(Duration::zero);
+        let unrestrict_at = starts_at + duration;
+        console_log!("[ModerationService] Muting user {} until {} (starting {}, by {})", user_id, unrestrict_at, starts_at, muted_by);
+
+        let sql = format!(
+            "INSERT INTO mutes (id, user_id, reason, muted_by, created_at, starts_at, unrestrict_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7) RETURNING {}",
+            Self::MUTE_FIELDS
+        );
+        self.db.prepare(&sql)
+            .bind(&[
+                id.clone().into(), user_id.into(), "muted by moderator".into(), muted_by.into(),
+                now.to_rfc3339().into(), starts_at.to_rfc3339().into(), unrestrict_at.to_rfc3339().into(),
+            ])?
+            .first::<Mute>(None).await?
+            .ok_or_else(|| worker::Error::RustError(format!("mute {} not found after insert", id)))
+    }
+
+    /// Removes all mute rows for `user_id`, lifting the restriction immediately.
+    pub async fn unmute(&self, user_id: &str) -> Result<()> {
+        console_log!("[ModerationService] Unmuting user {}", user_id);
+        self.db.prepare("DELETE FROM mutes WHERE user_id = ?1")
+            .bind(&[user_id.into()])?
+            .run().await?;
+        Ok(())
+    }
+
+    /// Deletes any mute rows for `user_id` whose `unrestrict_at` has already
+    /// passed, then returns whichever mute (if any) is active right now —
+    /// started (`starts_at` has passed) and not yet expired. Call this at
+    /// the top of message handling, since Workers have no background timer
+    /// to lift an expired mute on its own.
+    pub async fn check_and_lift_mute(&self, user_id: &str) -> Result<Option<Mute>> {
+        let now = Utc::now();
+        self.db.prepare("DELETE FROM mutes WHERE user_id = ?1 AND unrestrict_at <= ?2")
+            .bind(&[user_id.into(), now.to_rfc3339().into()])?
+            .run().await?;
+
+        let sql = format!(
+            "SELECT {} FROM mutes WHERE user_id = ?1 AND starts_at <= ?2 AND unrestrict_at > ?2 ORDER BY created_at DESC LIMIT 1",
+            Self::MUTE_FIELDS
+        );
+        let mute = self.db.prepare(&sql)
+            .bind(&[user_id.into(), now.to_rfc3339().into()])?
+            .first::<Mute>(None).await?;
+
+        Ok(mute)
+    }
+}
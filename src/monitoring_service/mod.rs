@@ -0,0 +1,112 @@
+// src/monitoring_service/mod.rs
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use worker::{Env, Fetch, Method, Request, Result, console_error, console_warn};
+
+use crate::config_service::ConfigService;
+use crate::user_service::UserService;
+
+/// When this isolate first answered a health check. Workers don't guarantee
+/// isolate reuse, so `HealthReport::uptime_seconds` tracks "time since this
+/// isolate started answering health checks", not true process uptime.
+static ISOLATE_STARTED_AT: OnceLock<DateTime<Utc>> = OnceLock::new();
+
+/// One dependency probed by `MonitoringService::health_check`.
+#[derive(Serialize, Debug, Clone)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub ok: bool,
+    pub latency_ms: u64,
+}
+
+/// Structured health status for `/status` and the `/health` HTTP path,
+/// so external uptime monitors can poll the worker directly instead of
+/// sending fake Telegram updates.
+#[derive(Serialize, Debug, Clone)]
+pub struct HealthReport {
+    pub status: &'static str,
+    pub checks: Vec<CheckResult>,
+    pub uptime_seconds: i64,
+}
+
+impl HealthReport {
+    pub fn is_healthy(&self) -> bool {
+        self.checks.iter().all(|c| c.ok)
+    }
+}
+
+pub struct MonitoringService;
+
+impl MonitoringService {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /// Probes D1 reachability (via `UserService`), config load success, and a
+    /// round-trip latency measurement to the Telegram API `getMe`.
+    pub async fn health_check(&self, env: &Env, user_service: &UserService) -> HealthReport {
+        let started_at = *ISOLATE_STARTED_AT.get_or_init(Utc::now);
+
+        let checks = vec![
+            Self::timed_check("d1", user_service.ping()).await,
+            Self::timed_check("config", async {
+                ConfigService::new().get_environment_config(env).await.map(|_| ())
+            }).await,
+            Self::telegram_get_me_check(env).await,
+        ];
+
+        let status = if checks.iter().all(|c| c.ok) { "healthy" } else { "unhealthy" };
+        let uptime_seconds = Utc::now().signed_duration_since(started_at).num_seconds();
+
+        HealthReport { status, checks, uptime_seconds }
+    }
+
+    async fn timed_check<F>(name: &'static str, probe: F) -> CheckResult
+    where
+        F: std::future::Future<Output = Result<()>>,
+    {
+        let start = Utc::now();
+        let ok = match probe.await {
+            Ok(()) => true,
+            Err(e) => {
+                console_error!("[MonitoringService] Check '{}' failed: {}", name, e);
+                false
+            }
+        };
+        let latency_ms = Utc::now().signed_duration_since(start).num_milliseconds().max(0) as u64;
+        CheckResult { name, ok, latency_ms }
+    }
+
+    async fn telegram_get_me_check(env: &Env) -> CheckResult {
+        let name = "telegram_api";
+        let start = Utc::now();
+
+        let token = match env.secret("TELEGRAM_BOT_TOKEN") {
+            Ok(t) => t.to_string(),
+            Err(e) => {
+                console_warn!("[MonitoringService] TELEGRAM_BOT_TOKEN not configured ({}); skipping getMe check.", e);
+                return CheckResult { name, ok: false, latency_ms: 0 };
+            }
+        };
+
+        let url = format!("https://api.telegram.org/bot{}/getMe", token);
+        let ok = match Request::new(&url, Method::Get) {
+            Ok(request) => match Fetch::Request(request).send().await {
+                Ok(response) => response.status_code() == 200,
+                Err(e) => {
+                    console_error!("[MonitoringService] getMe call failed: {}", e);
+                    false
+                }
+            },
+            Err(e) => {
+                console_error!("[MonitoringService] Failed to build getMe request: {}", e);
+                false
+            }
+        };
+
+        let latency_ms = Utc::now().signed_duration_since(start).num_milliseconds().max(0) as u64;
+        CheckResult { name, ok, latency_ms }
+    }
+}
@@ -1,48 +1,257 @@
 // src/rbac_service/mod.rs
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet; // For efficient permission lookups
-use worker::console_log;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet}; // For efficient permission lookups
+use worker::{D1Database, Env, Result};
 
+/// `GroupModerator`/`GroupAdmin` carry a chat/group id: unlike the global
+/// `Moderator`/`Admin` variants, they only grant their permissions when
+/// `RBACService::check_permission`'s `scope` matches that id — a moderator of
+/// one group isn't a moderator everywhere.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Role {
     User,
+    Moderator,
     Admin,
-    // Potentially other roles like Moderator, PremiumUser, etc.
+    GroupModerator(String),
+    GroupAdmin(String),
+    // Potentially other roles like PremiumUser, etc.
+}
+
+impl Role {
+    fn as_str(&self) -> String {
+        match self {
+            Role::User => "User".to_string(),
+            Role::Moderator => "Moderator".to_string(),
+            Role::Admin => "Admin".to_string(),
+            Role::GroupModerator(scope) => format!("GroupModerator:{}", scope),
+            Role::GroupAdmin(scope) => format!("GroupAdmin:{}", scope),
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "User" => Some(Role::User),
+            "Moderator" => Some(Role::Moderator),
+            "Admin" => Some(Role::Admin),
+            other => {
+                let (prefix, scope) = other.split_once(':')?;
+                match prefix {
+                    "GroupModerator" => Some(Role::GroupModerator(scope.to_string())),
+                    "GroupAdmin" => Some(Role::GroupAdmin(scope.to_string())),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+/// One row of the `user_roles` table: a single role grant for a user, optionally
+/// scoped (e.g. to a chat/group id) and optionally time-limited.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserRoleGrant {
+    pub user_id: String,
+    pub role: String,
+    pub scope: Option<String>,
+    pub granted_by: String,
+    pub granted_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// How a command's `command_restrictions` row gates access, independent of any
+/// single user's roles. Lets operators open or close a command at runtime
+/// without shipping new code.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PermissionLevel {
+    /// Anyone can run it.
+    Unrestricted,
+    /// Only roles listed in `command_role_grants` for this command can run it.
+    Managed,
+    /// Only `Role::Admin` can run it.
+    Restricted,
+}
+
+impl PermissionLevel {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PermissionLevel::Unrestricted => "Unrestricted",
+            PermissionLevel::Managed => "Managed",
+            PermissionLevel::Restricted => "Restricted",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "Unrestricted" => Some(PermissionLevel::Unrestricted),
+            "Managed" => Some(PermissionLevel::Managed),
+            "Restricted" => Some(PermissionLevel::Restricted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CommandRestrictionRow {
+    level: String,
+}
+
+#[derive(Deserialize)]
+struct CommandRoleGrantRow {
+    role: String,
+}
+
+/// A command's resolved access rule: its `PermissionLevel` plus, for
+/// `Managed` commands, the roles allowed to run it.
+#[derive(Debug, Clone)]
+struct CommandAccess {
+    level: PermissionLevel,
+    granted_roles: Vec<Role>,
 }
 
 pub struct RBACService {
-    // In the future, this might hold configurations or D1 bindings if roles/permissions are dynamic
+    db: D1Database,
+    /// Resolved `command_restrictions`/`command_role_grants` rows, cached for
+    /// the lifetime of this `RBACService` (one request) so a command checked
+    /// more than once per request (e.g. by both a hook and a handler) only
+    /// costs one query.
+    cache: RefCell<HashMap<String, CommandAccess>>,
 }
 
 impl RBACService {
-    pub fn new() -> Self {
-        RBACService {}
+    pub fn new(env: &Env) -> Result<Self> {
+        let db = env.d1("DB")?;
+        Ok(Self { db, cache: RefCell::new(HashMap::new()) })
+    }
+
+    /// Grants `role` to `user_id`, optionally scoped to `scope` (e.g. a chat id)
+    /// and optionally expiring at `expires_at`. Used to seed `default_user_roles()`
+    /// onto a new `User` row as well as for ad hoc temporary grants (e.g. a
+    /// time-limited reviewer role).
+    pub async fn grant_role(&self, user_id: &str, role: &Role, scope: Option<&str>, granted_by: &str, expires_at: Option<DateTime<Utc>>) -> Result<()> {
+        let now = Utc::now();
+        self.db.prepare(
+            "INSERT INTO user_roles (user_id, role, scope, granted_by, granted_at, expires_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+        )
+        .bind(&[
+            user_id.into(), role.as_str().into(), scope.into(), granted_by.into(),
+            now.to_rfc3339().into(), expires_at.map(|e| e.to_rfc3339()).into(),
+        ])?
+        .run().await?;
+        Ok(())
+    }
+
+    /// Coalesces every non-expired role grant for `user_id` — global and scoped
+    /// alike — into the active set. Authorization should go through this rather
+    /// than reading `User.roles` directly, so a temporary grant lapses on its own
+    /// once `expires_at` passes without a separate cleanup job.
+    pub async fn effective_roles(&self, user_id: &str) -> Result<Vec<Role>> {
+        let now = Utc::now();
+        let sql = "SELECT user_id, role, scope, granted_by, granted_at, expires_at FROM user_roles \
+                   WHERE user_id = ?1 AND (expires_at IS NULL OR expires_at > ?2)";
+        let results = self.db.prepare(sql).bind(&[user_id.into(), now.to_rfc3339().into()])?.all().await?;
+        let grants: Vec<UserRoleGrant> = results.results()?;
+        Ok(grants.into_iter().filter_map(|grant| Role::from_str(&grant.role)).collect())
+    }
+
+    /// Resolves `command`'s `PermissionLevel` and, for `Managed` commands, its
+    /// allowed roles, querying D1 once per command per `RBACService` instance.
+    async fn resolve_command_access(&self, command: &str) -> Result<CommandAccess> {
+        if let Some(cached) = self.cache.borrow().get(command) {
+            return Ok(cached.clone());
+        }
+
+        let level = match self.db.prepare("SELECT level FROM command_restrictions WHERE command = ?1")
+            .bind(&[command.into()])?
+            .first::<CommandRestrictionRow>(None).await?
+        {
+            Some(row) => PermissionLevel::from_str(&row.level).unwrap_or(PermissionLevel::Unrestricted),
+            None => PermissionLevel::Unrestricted,
+        };
+
+        let granted_roles = if level == PermissionLevel::Managed {
+            let results = self.db.prepare("SELECT role FROM command_role_grants WHERE command = ?1")
+                .bind(&[command.into()])?
+                .all().await?;
+            let rows: Vec<CommandRoleGrantRow> = results.results()?;
+            rows.into_iter().filter_map(|row| Role::from_str(&row.role)).collect()
+        } else {
+            Vec::new()
+        };
+
+        let access = CommandAccess { level, granted_roles };
+        self.cache.borrow_mut().insert(command.to_string(), access.clone());
+        Ok(access)
     }
 
-    pub fn check_permission(&self, user_roles: &[Role], command: &str) -> bool {
-        // console_log!("[RBACService] Checking permission for command '{}' with roles: {:?}", command, user_roles); // Can be verbose
+    /// Whether `user_role` satisfies `required` under `scope`. Exact matches
+    /// (including two matching `GroupModerator`/`GroupAdmin` ids) always
+    /// satisfy; a `GroupModerator`/`GroupAdmin` also satisfies its unscoped
+    /// counterpart when `scope` matches the chat/group id it was granted for.
+    fn role_satisfies(user_role: &Role, required: &Role, scope: Option<&str>) -> bool {
+        if user_role == required {
+            return true;
+        }
+        match (user_role, required) {
+            (Role::GroupModerator(granted_scope), Role::Moderator) => scope == Some(granted_scope.as_str()),
+            (Role::GroupAdmin(granted_scope), Role::Admin) => scope == Some(granted_scope.as_str()),
+            _ => false,
+        }
+    }
 
-        if user_roles.contains(&Role::Admin) {
-            // console_log!("[RBACService] Admin role found. Permission granted.");
+    /// Whether `user_roles` includes a global `Admin`, or a `GroupAdmin` whose
+    /// scope matches `scope`. Either short-circuits every permission check,
+    /// same as global `Admin` always has.
+    fn is_admin_in_scope(user_roles: &[Role], scope: Option<&str>) -> bool {
+        user_roles.iter().any(|role| match role {
+            Role::Admin => true,
+            Role::GroupAdmin(granted_scope) => scope == Some(granted_scope.as_str()),
+            _ => false,
+        })
+    }
+
+    /// Given an already-resolved `CommandAccess`, decides whether `user_roles`
+    /// may run the command under `scope`. Split out from `check_permission` so
+    /// the decision itself (pure, no D1) stays unit-testable without a database.
+    fn has_access(user_roles: &[Role], access: &CommandAccess, scope: Option<&str>) -> bool {
+        if Self::is_admin_in_scope(user_roles, scope) {
             return true;
         }
 
-        let user_allowed_commands: HashSet<&str> = [
-            "/start",
-            "/find_match",
-            "/profile",
-            "/help",
-            // "/feedback", // Example, if added later
-        ].iter().cloned().collect();
+        match access.level {
+            PermissionLevel::Unrestricted => true,
+            PermissionLevel::Restricted => false,
+            PermissionLevel::Managed => access.granted_roles.iter().any(|required| {
+                user_roles.iter().any(|user_role| Self::role_satisfies(user_role, required, scope))
+            }),
+        }
+    }
+
+    /// D1-backed permission check: an `Unrestricted` command passes for
+    /// anyone, `Restricted` requires `Role::Admin`, and `Managed` requires one
+    /// of the roles listed in `command_role_grants` for that command. Admins
+    /// always bypass. `scope` (e.g. the current chat id) lets a
+    /// `GroupModerator`/`GroupAdmin` grant apply only within its own group,
+    /// rather than an admin of one group being an admin everywhere. Lets
+    /// operators open/close commands like `/find_match` at runtime without
+    /// shipping new code.
+    ///
+    /// `base_roles` (the flat `User.roles` JSON) is combined with
+    /// `effective_roles(user_id)` — the non-expired `user_roles` table
+    /// grants — so a time-limited grant (e.g. a temporary reviewer role)
+    /// actually takes effect and automatically lapses once `expires_at`
+    /// passes, instead of only the permanently-seeded roles ever being
+    /// checked.
+    pub async fn check_permission(&self, user_id: &str, base_roles: &[Role], command: &str, scope: Option<&str>) -> Result<bool> {
+        let mut user_roles = base_roles.to_vec();
+        user_roles.extend(self.effective_roles(user_id).await?);
 
-        if user_roles.contains(&Role::User) {
-            let has_permission = user_allowed_commands.contains(command);
-            // console_log!("[RBACService] User role found. Permission for '{}': {}", command, has_permission);
-            return has_permission;
+        if Self::is_admin_in_scope(&user_roles, scope) {
+            return Ok(true);
         }
 
-        // console_log!("[RBACService] No matching roles or permissions. Permission denied for command '{}'.", command);
-        false
+        let access = self.resolve_command_access(command).await?;
+        Ok(Self::has_access(&user_roles, &access, scope))
     }
 
     pub fn is_admin_command(&self, command: &str) -> bool {
@@ -50,86 +259,142 @@ impl RBACService {
             "/admin_settings",
             "/view_users",
             "/bot_status",
+            "/grant_role",
+            "/revoke_role",
+            "/ban",
+            "/unban",
+            "/mute",
+            "/unmute",
         ].iter().cloned().collect();
         admin_commands.contains(command)
     }
-}
 
-impl Default for RBACService {
-    fn default() -> Self {
-        Self::new()
+    /// Ranks a role's seniority for `can_change_role`: `User` is lowest,
+    /// `Moderator`/`GroupModerator` sit above it, and `Admin`/`GroupAdmin` are
+    /// highest. Scoped variants rank alongside their global counterpart.
+    fn role_rank(role: &Role) -> u8 {
+        match role {
+            Role::User => 0,
+            Role::Moderator | Role::GroupModerator(_) => 1,
+            Role::Admin | Role::GroupAdmin(_) => 2,
+        }
+    }
+
+    /// Whether `actor_roles` may grant or revoke `role` on another user: the
+    /// actor's highest rank must strictly exceed `role`'s, so an admin can't
+    /// use this to hand out (or take away) another admin's `Admin` role.
+    pub fn can_change_role(actor_roles: &[Role], role: &Role) -> bool {
+        let actor_rank = actor_roles.iter().map(Self::role_rank).max().unwrap_or(0);
+        actor_rank > Self::role_rank(role)
     }
 }
 
+/// The outcome of an admin `/grant_role` or `/revoke_role` command, precise
+/// enough for the bot to reply with exactly what happened (or didn't).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChangeResult {
+    Success(String),
+    Failed(String),
+    NoChange(String),
+}
+
 
 #[cfg(test)]
 mod tests {
     use super::*; // Import items from outer module (RBACService, Role)
 
+    // `check_permission` itself is D1-backed and isn't exercised here (no D1
+    // binding in a unit test); these cover `has_access`, the pure decision it
+    // delegates to once `resolve_command_access` has resolved a command's row.
+
     #[test]
-    fn test_check_permission_admin_has_all_permissions() {
-        let rbac = RBACService::new();
+    fn test_has_access_admin_bypasses_every_level() {
         let admin_roles = vec![Role::Admin];
 
-        assert!(rbac.check_permission(&admin_roles, "/start"), "Admin should have /start permission");
-        assert!(rbac.check_permission(&admin_roles, "/find_match"), "Admin should have /find_match permission");
-        assert!(rbac.check_permission(&admin_roles, "/profile"), "Admin should have /profile permission");
-        assert!(rbac.check_permission(&admin_roles, "/help"), "Admin should have /help permission");
-        assert!(rbac.check_permission(&admin_roles, "/admin_settings"), "Admin should have /admin_settings permission");
-        assert!(rbac.check_permission(&admin_roles, "/view_users"), "Admin should have /view_users permission");
-        assert!(rbac.check_permission(&admin_roles, "/bot_status"), "Admin should have /bot_status permission");
-        assert!(rbac.check_permission(&admin_roles, "/some_undefined_command"), "Admin should have permission for any command");
+        for level in [PermissionLevel::Unrestricted, PermissionLevel::Managed, PermissionLevel::Restricted] {
+            let access = CommandAccess { level, granted_roles: vec![] };
+            assert!(RBACService::has_access(&admin_roles, &access, None), "Admin should bypass {:?}", level);
+        }
+    }
+
+    #[test]
+    fn test_has_access_unrestricted_allows_anyone() {
+        let access = CommandAccess { level: PermissionLevel::Unrestricted, granted_roles: vec![] };
+        assert!(RBACService::has_access(&[Role::User], &access, None));
+        assert!(RBACService::has_access(&[], &access, None));
+    }
+
+    #[test]
+    fn test_has_access_restricted_denies_non_admins() {
+        let access = CommandAccess { level: PermissionLevel::Restricted, granted_roles: vec![] };
+        assert!(!RBACService::has_access(&[Role::User], &access, None));
+        assert!(!RBACService::has_access(&[Role::Moderator], &access, None));
     }
 
     #[test]
-    fn test_check_permission_user_has_defined_permissions() {
-        let rbac = RBACService::new();
-        let user_roles = vec![Role::User];
+    fn test_has_access_managed_requires_a_granted_role() {
+        let access = CommandAccess { level: PermissionLevel::Managed, granted_roles: vec![Role::Moderator] };
+        assert!(RBACService::has_access(&[Role::Moderator], &access, None), "Moderator was granted this command");
+        assert!(!RBACService::has_access(&[Role::User], &access, None), "User was not granted this command");
+        assert!(!RBACService::has_access(&[], &access, None));
+    }
 
-        // Allowed commands
-        assert!(rbac.check_permission(&user_roles, "/start"), "User should have /start permission");
-        assert!(rbac.check_permission(&user_roles, "/find_match"), "User should have /find_match permission");
-        assert!(rbac.check_permission(&user_roles, "/profile"), "User should have /profile permission");
-        assert!(rbac.check_permission(&user_roles, "/help"), "User should have /help permission");
+    #[test]
+    fn test_has_access_group_moderator_only_in_its_own_scope() {
+        let access = CommandAccess { level: PermissionLevel::Managed, granted_roles: vec![Role::Moderator] };
+        let roles = vec![Role::GroupModerator("grp123".to_string())];
 
-        // Disallowed commands (admin or undefined)
-        assert!(!rbac.check_permission(&user_roles, "/admin_settings"), "User should NOT have /admin_settings permission");
-        assert!(!rbac.check_permission(&user_roles, "/view_users"), "User should NOT have /view_users permission");
-        assert!(!rbac.check_permission(&user_roles, "/bot_status"), "User should NOT have /bot_status permission");
-        assert!(!rbac.check_permission(&user_roles, "/some_undefined_command"), "User should NOT have permission for undefined command");
+        assert!(RBACService::has_access(&roles, &access, Some("grp123")), "Scope matches the grant");
+        assert!(!RBACService::has_access(&roles, &access, Some("grp999")), "Scope doesn't match the grant");
+        assert!(!RBACService::has_access(&roles, &access, None), "No scope means no group context");
     }
 
     #[test]
-    fn test_check_permission_user_with_admin_role_is_admin() {
-        let rbac = RBACService::new();
-        let user_admin_roles = vec![Role::User, Role::Admin];
+    fn test_has_access_group_admin_bypasses_only_in_its_own_scope() {
+        let access = CommandAccess { level: PermissionLevel::Restricted, granted_roles: vec![] };
+        let roles = vec![Role::GroupAdmin("grp123".to_string())];
 
-        assert!(rbac.check_permission(&user_admin_roles, "/start"), "User+Admin should have /start permission");
-        assert!(rbac.check_permission(&user_admin_roles, "/admin_settings"), "User+Admin should have /admin_settings permission");
-        assert!(rbac.check_permission(&user_admin_roles, "/some_undefined_command"), "User+Admin should have permission for any command");
+        assert!(RBACService::has_access(&roles, &access, Some("grp123")), "GroupAdmin should bypass Restricted in its own scope");
+        assert!(!RBACService::has_access(&roles, &access, Some("grp999")), "GroupAdmin of a different group shouldn't bypass");
+        assert!(!RBACService::has_access(&roles, &access, None), "GroupAdmin isn't a global admin");
     }
 
     #[test]
-    fn test_check_permission_no_roles_has_no_permissions() {
-        let rbac = RBACService::new();
-        let no_roles: Vec<Role> = vec![];
+    fn test_role_json_round_trips_scoped_variants() {
+        let roles = vec![Role::User, Role::GroupModerator("grp123".to_string()), Role::GroupAdmin("grp456".to_string())];
+        let json = serde_json::to_string(&roles).unwrap();
+        let round_tripped: Vec<Role> = serde_json::from_str(&json).unwrap();
+        assert_eq!(roles, round_tripped);
+    }
 
-        assert!(!rbac.check_permission(&no_roles, "/start"), "No roles should NOT have /start permission");
-        assert!(!rbac.check_permission(&no_roles, "/admin_settings"), "No roles should NOT have /admin_settings permission");
-        assert!(!rbac.check_permission(&no_roles, "/some_undefined_command"), "No roles should NOT have permission for undefined command");
+    #[test]
+    fn test_permission_level_round_trips_through_str() {
+        for level in [PermissionLevel::Unrestricted, PermissionLevel::Managed, PermissionLevel::Restricted] {
+            assert_eq!(PermissionLevel::from_str(level.as_str()), Some(level));
+        }
+        assert_eq!(PermissionLevel::from_str("Bogus"), None);
     }
 
     #[test]
     fn test_is_admin_command_positive_cases() {
-        let rbac = RBACService::new();
+        let rbac = RBACService::new(&worker::Env::empty()).unwrap();
         assert!(rbac.is_admin_command("/admin_settings"));
         assert!(rbac.is_admin_command("/view_users"));
         assert!(rbac.is_admin_command("/bot_status"));
     }
 
+    #[test]
+    fn test_can_change_role_requires_strictly_higher_rank() {
+        assert!(RBACService::can_change_role(&[Role::Admin], &Role::Moderator));
+        assert!(RBACService::can_change_role(&[Role::Moderator], &Role::User));
+        assert!(!RBACService::can_change_role(&[Role::Admin], &Role::Admin), "An admin can't grant/revoke Admin via this");
+        assert!(!RBACService::can_change_role(&[Role::Moderator], &Role::Moderator));
+        assert!(!RBACService::can_change_role(&[Role::User], &Role::Moderator));
+    }
+
     #[test]
     fn test_is_admin_command_negative_cases() {
-        let rbac = RBACService::new();
+        let rbac = RBACService::new(&worker::Env::empty()).unwrap();
         assert!(!rbac.is_admin_command("/start"));
         assert!(!rbac.is_admin_command("/profile"));
         assert!(!rbac.is_admin_command("admin_settings")); // Missing slash
@@ -1,7 +1,10 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use worker::{D1Database, Env, Result, D1PreparedStatement, console_log, console_warn, console_error};
 use crate::rbac_service::Role;
+use crate::consent_service::{ConsentService, ConsentType};
+use crate::ban_service::{Ban, BanService};
 
 // Constants
 pub const MAX_USER_MEDIA_ITEMS: usize = 5;
@@ -26,6 +29,10 @@ pub(crate) fn default_media_keys() -> Vec<String> {
     vec![]
 }
 
+pub(crate) fn default_interests() -> Vec<String> {
+    vec![]
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
     pub id: String,
@@ -40,6 +47,10 @@ pub struct User {
     pub longitude: Option<f64>,
     #[serde(default = "default_media_keys")]
     pub media_keys: Vec<String>,
+    /// Free-text interest tags (e.g. "hiking", "jazz") used by `matching_service`
+    /// to score compatibility against another user's tags.
+    #[serde(default = "default_interests")]
+    pub interests: Vec<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
     pub last_interaction_at: DateTime<Utc>,
@@ -69,24 +80,43 @@ pub struct UserProfileUpdate {
 }
 
 
+/// One append-only `profile_history` row: the prior and new value of a single
+/// field on a single update, for moderation review and `revert_field`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ProfileHistoryEntry {
+    pub id: String,
+    pub user_id: String,
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+    pub changed_at: DateTime<Utc>,
+    pub changed_by: Option<String>,
+}
+
 pub struct UserService {
     db: D1Database,
 }
 
 impl UserService {
     const USER_FIELDS_FOR_RETURNING: &'static str =
-        "id, telegram_id, telegram_username, name, age, gender, bio, location_text, latitude, longitude, media_keys, created_at, updated_at, last_interaction_at, state, roles";
+        "id, telegram_id, telegram_username, name, age, gender, bio, location_text, latitude, longitude, media_keys, interests, created_at, updated_at, last_interaction_at, state, roles";
 
     pub fn new(env: &Env) -> Result<Self> {
         let db = env.d1("DB")?;
         Ok(Self { db })
     }
 
+    /// Cheap D1 reachability probe for `monitoring_service::MonitoringService::health_check`.
+    pub async fn ping(&self) -> Result<()> {
+        self.db.prepare("SELECT 1 as one").first::<std::collections::HashMap<String, i64>>(None).await?;
+        Ok(())
+    }
+
     pub async fn get_user_by_telegram_id(&self, telegram_id: i64) -> Result<Option<User>> {
         let sql = format!("SELECT {} FROM users WHERE telegram_id = ?1 LIMIT 1", Self::USER_FIELDS_FOR_RETURNING);
         let statement = self.db.prepare(&sql);
         match statement.bind(&[telegram_id.into()])?.first::<User>(None).await {
-            Ok(Some(user)) => Ok(Some(user)),
+            Ok(Some(mut user)) => { user.media_keys = self.load_media_keys(&user.id).await?; Ok(Some(user)) }
             Ok(None) => Ok(None),
             Err(e) => { console_error!("[UserService] Error get_user_by_telegram_id for {}: {}", telegram_id, e); Err(e.into()) }
         }
@@ -97,100 +127,261 @@ impl UserService {
         let sql = format!("SELECT {} FROM users WHERE id = ?1 LIMIT 1", Self::USER_FIELDS_FOR_RETURNING);
         let statement = self.db.prepare(&sql);
         match statement.bind(&[user_id.into()])?.first::<User>(None).await {
-            Ok(Some(user)) => Ok(Some(user)),
+            Ok(Some(mut user)) => { user.media_keys = self.load_media_keys(&user.id).await?; Ok(Some(user)) }
             Ok(None) => Ok(None),
             Err(e) => { console_error!("[UserService] Error querying user by internal id {}: {}", user_id, e); Err(e.into()) }
         }
     }
 
-    pub async fn create_user_from_telegram_user(&self, telegram_user: &crate::TelegramUser) -> Result<User> {
+    /// Projects ordered `r2_object_key`s from the normalized `user_media` table so
+    /// `User.media_keys` keeps working for callers that predate that table, without
+    /// reading/writing the whole user row to mutate a single attachment.
+    async fn load_media_keys(&self, user_id: &str) -> Result<Vec<String>> {
+        let sql = "SELECT r2_object_key FROM user_media WHERE user_id = ?1 ORDER BY position ASC";
+        let results = self.db.prepare(sql).bind(&[user_id.into()])?.all().await?;
+        let rows: Vec<std::collections::HashMap<String, String>> = results.results()?;
+        Ok(rows.into_iter().filter_map(|mut row| row.remove("r2_object_key")).collect())
+    }
+
+    /// Creates a new user and seeds `default_user_roles()` into `rbac_service`'s
+    /// `user_roles` table (global, non-expiring grants) so `effective_roles`
+    /// agrees with the embedded `roles` column from the start.
+    /// Finds users within `radius_km` of `(center_lat, center_lng)`. Pre-filters
+    /// with an index-friendly bounding box in SQL, then refines in Rust with the
+    /// haversine distance, returning the nearest `limit` candidates paired with
+    /// their distance in km, ascending.
+    pub async fn find_nearby_users(&self, center_lat: f64, center_lng: f64, radius_km: f64, limit: u32) -> Result<Vec<(User, f64)>> {
+        let lat_delta = radius_km / 111.0;
+        let lng_delta = radius_km / (111.0 * center_lat.to_radians().cos().max(0.000001));
+
+        let min_lat = center_lat - lat_delta;
+        let max_lat = center_lat + lat_delta;
+        let min_lng = center_lng - lng_delta;
+        let max_lng = center_lng + lng_delta;
+
+        let sql = format!(
+            "SELECT {} FROM users \
+             WHERE latitude IS NOT NULL AND longitude IS NOT NULL \
+             AND latitude BETWEEN ?1 AND ?2 AND longitude BETWEEN ?3 AND ?4",
+            Self::USER_FIELDS_FOR_RETURNING
+        );
+        let results = self.db.prepare(&sql)
+            .bind(&[min_lat.into(), max_lat.into(), min_lng.into(), max_lng.into()])?
+            .all().await?;
+        let candidates: Vec<User> = results.results()?;
+
+        let mut with_distance: Vec<(User, f64)> = candidates.into_iter()
+            .filter_map(|user| {
+                let (lat, lng) = (user.latitude?, user.longitude?);
+                let distance_km = haversine_distance_km(center_lat, center_lng, lat, lng);
+                (distance_km <= radius_km).then_some((user, distance_km))
+            })
+            .collect();
+
+        with_distance.sort_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        with_distance.truncate(limit as usize);
+        Ok(with_distance)
+    }
+
+    pub async fn create_user_from_telegram_user(&self, telegram_user: &crate::TelegramUser, rbac_service: &crate::rbac_service::RBACService) -> Result<User> {
         let user_id = worker::Uuid::new_v4().to_string();
         let now = Utc::now();
         let new_user = User {
             id: user_id.clone(), telegram_id: telegram_user.id, telegram_username: telegram_user.username.clone(),
             name: None, age: None, gender: None, bio: None, location_text: None, latitude: None, longitude: None,
-            media_keys: default_media_keys(), created_at: now, updated_at: now, last_interaction_at: now,
+            media_keys: default_media_keys(), interests: default_interests(), created_at: now, updated_at: now, last_interaction_at: now,
             state: UserState::Onboarding, roles: default_user_roles(),
         };
         console_log!("[UserService] Creating user: id={}", new_user.id);
         let roles_json = serde_json::to_string(&new_user.roles)?;
         let media_keys_json = serde_json::to_string(&new_user.media_keys)?;
+        let interests_json = serde_json::to_string(&new_user.interests)?;
         let state_str = serde_json::to_string(&new_user.state)?.trim_matches('"').to_string();
-        let sql = "INSERT INTO users (id, telegram_id, telegram_username, name, age, gender, bio, location_text, latitude, longitude, media_keys, created_at, updated_at, last_interaction_at, state, roles) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)";
+        let sql = "INSERT INTO users (id, telegram_id, telegram_username, name, age, gender, bio, location_text, latitude, longitude, media_keys, interests, created_at, updated_at, last_interaction_at, state, roles) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)";
         let query = self.db.prepare(sql).bind(&[
             new_user.id.clone().into(), new_user.telegram_id.into(), new_user.telegram_username.clone().into(),
             new_user.name.clone().into(), new_user.age.into(), new_user.gender.clone().into(), new_user.bio.clone().into(),
             new_user.location_text.clone().into(), new_user.latitude.into(), new_user.longitude.into(),
-            media_keys_json.into(), now.to_rfc3339().into(), now.to_rfc3339().into(), now.to_rfc3339().into(),
+            media_keys_json.into(), interests_json.into(), now.to_rfc3339().into(), now.to_rfc3339().into(), now.to_rfc3339().into(),
             state_str.into(), roles_json.into(),
         ])?;
         query.run().await.map_err(|e| { console_error!("[UserService] Error creating user {}: {}", new_user.telegram_id, e); e.into() })?;
+
+        for role in &new_user.roles {
+            if let Err(e) = rbac_service.grant_role(&new_user.id, role, None, "system", None).await {
+                console_warn!("[UserService] Failed to seed effective role {:?} for {}: {}", role, new_user.id, e);
+            }
+        }
+
         Ok(new_user)
     }
 
-    pub async fn update_user_name(&self, user_id: &str, name: String) -> Result<User> { /* ... */ let now=Utc::now(); let sql=format!("UPDATE users SET name=?1,updated_at=?2,last_interaction_at=?2 WHERE id=?3 RETURNING {}",Self::USER_FIELDS_FOR_RETURNING); self.db.prepare(&sql).bind(&[name.into(),now.to_rfc3339().into(),user_id.into()])?.first(None).await?.ok_or_else(||Error::RustError(format!("User {} not found after name update",user_id)))}
-    pub async fn update_user_age(&self, user_id: &str, age: u8) -> Result<User> { /* ... */ let now=Utc::now(); let sql=format!("UPDATE users SET age=?1,updated_at=?2,last_interaction_at=?2 WHERE id=?3 RETURNING {}",Self::USER_FIELDS_FOR_RETURNING); self.db.prepare(&sql).bind(&[age.into(),now.to_rfc3339().into(),user_id.into()])?.first(None).await?.ok_or_else(||Error::RustError(format!("User {} not found after age update",user_id)))}
-    pub async fn update_user_gender(&self, user_id: &str, gender: String) -> Result<User> { /* ... */ let now=Utc::now(); let sql=format!("UPDATE users SET gender=?1,updated_at=?2,last_interaction_at=?2 WHERE id=?3 RETURNING {}",Self::USER_FIELDS_FOR_RETURNING); self.db.prepare(&sql).bind(&[gender.into(),now.to_rfc3339().into(),user_id.into()])?.first(None).await?.ok_or_else(||Error::RustError(format!("User {} not found after gender update",user_id)))}
-    pub async fn update_user_bio(&self, user_id: &str, bio: String) -> Result<User> { /* ... */ let now=Utc::now(); let sql=format!("UPDATE users SET bio=?1,updated_at=?2,last_interaction_at=?2 WHERE id=?3 RETURNING {}",Self::USER_FIELDS_FOR_RETURNING); self.db.prepare(&sql).bind(&[bio.into(),now.to_rfc3339().into(),user_id.into()])?.first(None).await?.ok_or_else(||Error::RustError(format!("User {} not found after bio update",user_id)))}
-    pub async fn update_user_location(&self, user_id: &str, location_text: Option<String>, latitude: Option<f64>, longitude: Option<f64>) -> Result<User> { /* ... */ let now=Utc::now(); let sql=format!("UPDATE users SET location_text=?1,latitude=?2,longitude=?3,updated_at=?4,last_interaction_at=?4 WHERE id=?5 RETURNING {}",Self::USER_FIELDS_FOR_RETURNING); self.db.prepare(&sql).bind(&[location_text.into(),latitude.into(),longitude.into(),now.to_rfc3339().into(),user_id.into()])?.first(None).await?.ok_or_else(||Error::RustError(format!("User {} not found after location update",user_id)))}
-    pub async fn update_user_state_and_name(&self, user_id: String, new_name: Option<String>, new_state: UserState) -> Result<User> { /* ... */ let now=Utc::now(); let sql=format!("UPDATE users SET name=?1,state=?2,updated_at=?3,last_interaction_at=?3 WHERE id=?4 RETURNING {}",Self::USER_FIELDS_FOR_RETURNING); let state_str=serde_json::to_string(&new_state)?.trim_matches('"').to_string(); self.db.prepare(&sql).bind(&[new_name.into(),state_str.into(),now.to_rfc3339().into(),user_id.clone().into()])?.first::<User>(None).await?.ok_or_else(||Error::RustError(format!("User {} not found after state/name update",user_id)))}
-    pub async fn record_user_interaction(&self, user_id: &str) -> Result<()> { /* ... */ let now=Utc::now(); let stmt=self.db.prepare("UPDATE users SET updated_at=?1,last_interaction_at=?1 WHERE id=?2"); query_result_to_unit(stmt.bind(&[now.to_rfc3339().into(),user_id.into()])?.run().await,"record_user_interaction",user_id)}
+    /// Inserts a `profile_history` row for `field` in the same D1 batch as
+    /// `update_stmt`, so the prior value is recorded atomically with the write
+    /// that overwrites it. Returns the user row from `update_stmt`'s `RETURNING`.
+    async fn update_with_history(
+        &self, user_id: &str, field: &str, old_value: Option<String>, new_value: Option<String>,
+        changed_by: Option<&str>, update_stmt: D1PreparedStatement,
+    ) -> Result<User> {
+        let history_id = worker::Uuid::new_v4().to_string();
+        let now = Utc::now();
+        let history_stmt = self.db.prepare(
+            "INSERT INTO profile_history (id, user_id, field, old_value, new_value, changed_at, changed_by) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+        ).bind(&[
+            history_id.into(), user_id.into(), field.into(), old_value.into(), new_value.into(),
+            now.to_rfc3339().into(), changed_by.into(),
+        ])?;
 
-    // --- Media Key Management ---
-    pub async fn add_media_key_to_user(&self, user_id: &str, r2_object_key: String) -> Result<User> {
-        console_log!("[UserService] Adding media key '{}' for user_id: {}", r2_object_key, user_id);
-        let mut current_user = self.get_user_by_id(user_id).await?
-            .ok_or_else(|| worker::Error::RustError(format!("User not found with id: {}", user_id)))?;
-
-        if current_user.media_keys.len() >= MAX_USER_MEDIA_ITEMS {
-            console_warn!("[UserService] User {} media limit ({}) reached. Cannot add key '{}'.", user_id, MAX_USER_MEDIA_ITEMS, r2_object_key);
-            return Err(worker::Error::RustError(format!("Media limit ({}) reached.", MAX_USER_MEDIA_ITEMS)));
-        }
+        let results = self.db.batch(vec![history_stmt, update_stmt]).await?;
+        results.into_iter().nth(1)
+            .and_then(|r| r.results::<User>().ok())
+            .and_then(|rows| rows.into_iter().next())
+            .ok_or_else(|| Error::RustError(format!("User {} not found after {} update", user_id, field)))
+    }
 
-        if !current_user.media_keys.contains(&r2_object_key) {
-            current_user.media_keys.push(r2_object_key);
-        } else {
-            console_warn!("[UserService] Media key already exists for user {}. Not adding duplicate.", user_id);
-        }
+    pub async fn update_user_name(&self, user_id: &str, name: String, changed_by: Option<&str>) -> Result<User> {
+        let old_value = self.get_user_by_id(user_id).await?.and_then(|u| u.name);
+        let now = Utc::now();
+        let sql = format!("UPDATE users SET name=?1,updated_at=?2,last_interaction_at=?2 WHERE id=?3 RETURNING {}", Self::USER_FIELDS_FOR_RETURNING);
+        let update_stmt = self.db.prepare(&sql).bind(&[name.clone().into(), now.to_rfc3339().into(), user_id.into()])?;
+        self.update_with_history(user_id, "name", old_value, Some(name), changed_by, update_stmt).await
+    }
 
+    pub async fn update_user_age(&self, user_id: &str, age: u8, changed_by: Option<&str>) -> Result<User> {
+        let old_value = self.get_user_by_id(user_id).await?.and_then(|u| u.age).map(|a| a.to_string());
         let now = Utc::now();
-        let updated_media_keys_json = serde_json::to_string(&current_user.media_keys)?;
-        let sql = format!("UPDATE users SET media_keys = ?1, updated_at = ?2, last_interaction_at = ?2 WHERE id = ?3 RETURNING {}", Self::USER_FIELDS_FOR_RETURNING);
+        let sql = format!("UPDATE users SET age=?1,updated_at=?2,last_interaction_at=?2 WHERE id=?3 RETURNING {}", Self::USER_FIELDS_FOR_RETURNING);
+        let update_stmt = self.db.prepare(&sql).bind(&[age.into(), now.to_rfc3339().into(), user_id.into()])?;
+        self.update_with_history(user_id, "age", old_value, Some(age.to_string()), changed_by, update_stmt).await
+    }
 
-        self.db.prepare(&sql)
-            .bind(&[updated_media_keys_json.into(), now.to_rfc3339().into(), user_id.into()])?
-            .first(None).await?
-            .ok_or_else(|| worker::Error::RustError(format!("User not found after adding media key: {}", user_id)))
+    pub async fn update_user_gender(&self, user_id: &str, gender: String, changed_by: Option<&str>) -> Result<User> {
+        let old_value = self.get_user_by_id(user_id).await?.and_then(|u| u.gender);
+        let now = Utc::now();
+        let sql = format!("UPDATE users SET gender=?1,updated_at=?2,last_interaction_at=?2 WHERE id=?3 RETURNING {}", Self::USER_FIELDS_FOR_RETURNING);
+        let update_stmt = self.db.prepare(&sql).bind(&[gender.clone().into(), now.to_rfc3339().into(), user_id.into()])?;
+        self.update_with_history(user_id, "gender", old_value, Some(gender), changed_by, update_stmt).await
+    }
+
+    pub async fn update_user_bio(&self, user_id: &str, bio: String, changed_by: Option<&str>) -> Result<User> {
+        let old_value = self.get_user_by_id(user_id).await?.and_then(|u| u.bio);
+        let now = Utc::now();
+        let sql = format!("UPDATE users SET bio=?1,updated_at=?2,last_interaction_at=?2 WHERE id=?3 RETURNING {}", Self::USER_FIELDS_FOR_RETURNING);
+        let update_stmt = self.db.prepare(&sql).bind(&[bio.clone().into(), now.to_rfc3339().into(), user_id.into()])?;
+        self.update_with_history(user_id, "bio", old_value, Some(bio), changed_by, update_stmt).await
+    }
+
+    pub async fn update_user_location(&self, user_id: &str, location_text: Option<String>, latitude: Option<f64>, longitude: Option<f64>, changed_by: Option<&str>) -> Result<User> {
+        let old_value = self.get_user_by_id(user_id).await?
+            .map(|u| json!({"location_text": u.location_text, "latitude": u.latitude, "longitude": u.longitude}).to_string());
+        let new_value = json!({"location_text": location_text, "latitude": latitude, "longitude": longitude}).to_string();
+        let now = Utc::now();
+        let sql = format!("UPDATE users SET location_text=?1,latitude=?2,longitude=?3,updated_at=?4,last_interaction_at=?4 WHERE id=?5 RETURNING {}", Self::USER_FIELDS_FOR_RETURNING);
+        let update_stmt = self.db.prepare(&sql).bind(&[location_text.into(), latitude.into(), longitude.into(), now.to_rfc3339().into(), user_id.into()])?;
+        self.update_with_history(user_id, "location", old_value, Some(new_value), changed_by, update_stmt).await
     }
 
-    pub async fn remove_media_key_from_user(&self, user_id: &str, r2_object_key_to_remove: &str) -> Result<User> {
-        console_log!("[UserService] Removing media key '{}' for user_id: {}", r2_object_key_to_remove, user_id);
-        let mut current_user = self.get_user_by_id(user_id).await?
-            .ok_or_else(|| worker::Error::RustError(format!("User not found with id: {}", user_id)))?;
+    pub async fn update_user_roles(&self, user_id: &str, roles: Vec<Role>, changed_by: Option<&str>) -> Result<User> {
+        let old_value = self.get_user_by_id(user_id).await?
+            .map(|u| serde_json::to_string(&u.roles)).transpose()?;
+        let new_value = serde_json::to_string(&roles)?;
+        let now = Utc::now();
+        let sql = format!("UPDATE users SET roles=?1,updated_at=?2,last_interaction_at=?2 WHERE id=?3 RETURNING {}", Self::USER_FIELDS_FOR_RETURNING);
+        let update_stmt = self.db.prepare(&sql).bind(&[new_value.clone().into(), now.to_rfc3339().into(), user_id.into()])?;
+        self.update_with_history(user_id, "roles", old_value, Some(new_value), changed_by, update_stmt).await
+    }
 
-        let initial_len = current_user.media_keys.len();
-        current_user.media_keys.retain(|key| key != r2_object_key_to_remove);
+    /// Returns the most recent `profile_history` rows for `user_id`, newest first,
+    /// for moderators reviewing who changed a field and when.
+    pub async fn get_profile_history(&self, user_id: &str, limit: u32) -> Result<Vec<ProfileHistoryEntry>> {
+        let sql = "SELECT id, user_id, field, old_value, new_value, changed_at, changed_by FROM profile_history \
+                   WHERE user_id = ?1 ORDER BY changed_at DESC LIMIT ?2";
+        let results = self.db.prepare(sql).bind(&[user_id.into(), limit.into()])?.all().await?;
+        results.results::<ProfileHistoryEntry>()
+    }
 
-        if current_user.media_keys.len() == initial_len && initial_len > 0 { // Check initial_len > 0 to ensure a key was meant to be removed
-             if !current_user.media_keys.contains(&r2_object_key_to_remove) { // If key wasn't in the list to begin with
-                console_warn!("[UserService] Media key '{}' not found for user {}. No DB change for media_keys.", r2_object_key_to_remove, user_id);
-             } // If key was present and list is now shorter, it was removed.
+    /// Restores `history_id`'s `old_value` onto the live `users` row for the
+    /// field it recorded, recording the revert itself as a new history entry.
+    pub async fn revert_field(&self, user_id: &str, history_id: &str) -> Result<User> {
+        let sql = "SELECT id, user_id, field, old_value, new_value, changed_at, changed_by FROM profile_history WHERE id = ?1 AND user_id = ?2";
+        let entry = self.db.prepare(sql).bind(&[history_id.into(), user_id.into()])?
+            .first::<ProfileHistoryEntry>(None).await?
+            .ok_or_else(|| Error::RustError(format!("profile_history row {} not found for user {}", history_id, user_id)))?;
+
+        match entry.field.as_str() {
+            "name" => self.update_user_name(user_id, entry.old_value.unwrap_or_default(), Some("revert")).await,
+            "gender" => self.update_user_gender(user_id, entry.old_value.unwrap_or_default(), Some("revert")).await,
+            "bio" => self.update_user_bio(user_id, entry.old_value.unwrap_or_default(), Some("revert")).await,
+            "age" => {
+                let age: u8 = entry.old_value.unwrap_or_default().parse()
+                    .map_err(|_| Error::RustError(format!("non-numeric age in history row {}", history_id)))?;
+                self.update_user_age(user_id, age, Some("revert")).await
+            }
+            other => Err(Error::RustError(format!("revert_field does not support field '{}'", other))),
         }
+    }
+    pub async fn update_user_state_and_name(&self, user_id: String, new_name: Option<String>, new_state: UserState) -> Result<User> { /* ... */ let now=Utc::now(); let sql=format!("UPDATE users SET name=?1,state=?2,updated_at=?3,last_interaction_at=?3 WHERE id=?4 RETURNING {}",Self::USER_FIELDS_FOR_RETURNING); let state_str=serde_json::to_string(&new_state)?.trim_matches('"').to_string(); self.db.prepare(&sql).bind(&[new_name.into(),state_str.into(),now.to_rfc3339().into(),user_id.clone().into()])?.first::<User>(None).await?.ok_or_else(||Error::RustError(format!("User {} not found after state/name update",user_id)))}
+    pub async fn record_user_interaction(&self, user_id: &str) -> Result<()> { /* ... */ let now=Utc::now(); let stmt=self.db.prepare("UPDATE users SET updated_at=?1,last_interaction_at=?1 WHERE id=?2"); query_result_to_unit(stmt.bind(&[now.to_rfc3339().into(),user_id.into()])?.run().await,"record_user_interaction",user_id)}
 
+    /// Loads `target_user_id` as `viewer_user_id` would see it: `latitude`/
+    /// `longitude` are cleared unless the target has granted `ShareLocation` to
+    /// the viewer, and `media_keys` is cleared unless `ShareMedia` was granted.
+    /// The owner always sees their own full profile.
+    pub async fn get_visible_profile(&self, viewer_user_id: &str, target_user_id: &str, consent_service: &ConsentService) -> Result<Option<User>> {
+        let mut user = match self.get_user_by_id(target_user_id).await? {
+            Some(u) => u,
+            None => return Ok(None),
+        };
 
-        let now = Utc::now();
-        let updated_media_keys_json = serde_json::to_string(&current_user.media_keys)?;
-        let sql = format!("UPDATE users SET media_keys = ?1, updated_at = ?2, last_interaction_at = ?2 WHERE id = ?3 RETURNING {}", Self::USER_FIELDS_FOR_RETURNING);
+        if viewer_user_id == target_user_id {
+            return Ok(Some(user));
+        }
 
-        self.db.prepare(&sql)
-            .bind(&[updated_media_keys_json.into(), now.to_rfc3339().into(), user_id.into()])?
-            .first(None).await?
-            .ok_or_else(|| worker::Error::RustError(format!("User not found after removing media key: {}", user_id)))
+        if !consent_service.has_consent(target_user_id, viewer_user_id, ConsentType::ShareLocation).await? {
+            user.latitude = None;
+            user.longitude = None;
+        }
+        if !consent_service.has_consent(target_user_id, viewer_user_id, ConsentType::ShareMedia).await? {
+            user.media_keys.clear();
+        }
+
+        Ok(Some(user))
     }
+
+    /// Like `get_user_by_id`, but also resolves the user's active ban (if any)
+    /// via `BanService` so callers can surface ban metadata (reason, expiry)
+    /// without a second round trip of their own.
+    pub async fn get_user_with_ban(&self, user_id: &str, ban_service: &BanService) -> Result<Option<(User, Option<Ban>)>> {
+        let user = match self.get_user_by_id(user_id).await? {
+            Some(u) => u,
+            None => return Ok(None),
+        };
+        let ban = ban_service.is_banned(user_id).await?;
+        Ok(Some((user, ban)))
+    }
+
+    // --- Media Key Management ---
+    // Mutations now live on `media_service::MediaService::add_user_media` /
+    // `remove_user_media`, which write individual `user_media` rows instead of
+    // reading-modifying-writing the whole `media_keys` JSON column. `media_keys`
+    // above is kept populated for backward compatibility via `load_media_keys`.
 }
 
 fn query_result_to_unit<T>(result: Result<worker::d1::D1Result<T>>, operation_name: &str, user_id: &str) -> Result<()> { match result{Ok(_)=>Ok(()),Err(e)=>{console_error!("[UserService] Error in {} for {}: {}",operation_name,user_id,e);Err(e.into())}} }
 
+/// Great-circle distance between two lat/lng points in kilometers.
+pub(crate) fn haversine_distance_km(lat1: f64, lng1: f64, lat2: f64, lng2: f64) -> f64 {
+    const EARTH_RADIUS_KM: f64 = 6371.0;
+    let (lat1_r, lat2_r) = (lat1.to_radians(), lat2.to_radians());
+    let delta_lat = (lat2 - lat1).to_radians();
+    let delta_lng = (lng2 - lng1).to_radians();
+
+    let a = (delta_lat / 2.0).sin().powi(2)
+        + lat1_r.cos() * lat2_r.cos() * (delta_lng / 2.0).sin().powi(2);
+    let c = 2.0 * a.sqrt().asin();
+    EARTH_RADIUS_KM * c
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -203,7 +394,7 @@ mod tests {
             id: "media_test_user".to_string(),
             media_keys,
             telegram_id: 123, telegram_username: None, name: None, age: None, gender: None, bio: None,
-            location_text: None, latitude: None, longitude: None, created_at: now,
+            location_text: None, latitude: None, longitude: None, interests: default_interests(), created_at: now,
             updated_at: now, last_interaction_at: now, state: UserState::Active, roles: vec![Role::User],
         }
     }
@@ -273,7 +464,7 @@ mod tests {
         User {
             id: "test_user_min_id".to_string(), telegram_id: 12345, telegram_username: Some("testuser".to_string()),
             name, age: None, gender: None, bio: None, location_text: None, latitude: None, longitude: None,
-            media_keys: default_media_keys(), created_at: now, updated_at: now, last_interaction_at: now,
+            media_keys: default_media_keys(), interests: default_interests(), created_at: now, updated_at: now, last_interaction_at: now,
             state, roles: default_user_roles(),
         }
     }
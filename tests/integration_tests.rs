@@ -196,3 +196,31 @@ async fn test_profile_media_deletion_flow() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+async fn test_roles_column_round_trips_scoped_role() -> Result<()> {
+    let mf = create_test_miniflare().await?;
+
+    let user_telegram_id = 778899001i64;
+    let user_internal_id = "test-user-scoped-role-01";
+    // Matches `Role`'s derived serde representation: unit variants as plain
+    // strings, the scoped `GroupModerator(String)` variant as `{"GroupModerator": <scope>}`.
+    let roles_with_scope = json!(["User", {"GroupModerator": "grp123"}]);
+    let roles_json = serde_json::to_string(&roles_with_scope)?;
+
+    println!("Scoped Role Test - Step 0: Seeding D1 for user_telegram_id: {}", user_telegram_id);
+    mf.d1_exec("DB", D1Exec::new(
+        "INSERT INTO users (id, telegram_id, name, state, roles, media_keys, created_at, updated_at, last_interaction_at, telegram_username)
+         VALUES (?1, ?2, 'ScopedRoleUser', 'Active', ?3, '[]', datetime('now'), datetime('now'), datetime('now'), ?4)"
+    ).bind_text(user_internal_id)?.bind_int(user_telegram_id)?.bind_text(&roles_json)?.bind_text("scoped_role_tg")?)
+    .await?;
+
+    let rows: Vec<TestUser> = mf.d1_query("DB", D1Query::new("SELECT roles FROM users WHERE id = ?1").bind_text(user_internal_id)?).await?;
+    assert_eq!(rows.len(), 1, "Seeded user not found.");
+
+    let round_tripped: serde_json::Value = serde_json::from_str(&rows[0].roles)?;
+    assert_eq!(round_tripped, roles_with_scope, "Scoped role did not round-trip through the D1 roles column unchanged.");
+    assert_eq!(round_tripped[1]["GroupModerator"], "grp123", "Scoped role's chat id did not survive the round-trip.");
+
+    Ok(())
+}